@@ -0,0 +1,163 @@
+//! Parses lines in klogger's default layout
+//! (`{timestamp} [{level}] - {target}: {message}`), optionally with the CPU
+//! id after the level and the source location after the target.
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Level {
+    Error = 1,
+    Warn,
+    Info,
+    Debug,
+    Trace,
+}
+
+impl Level {
+    pub fn parse(s: &str) -> Option<Level> {
+        Some(match s.to_ascii_lowercase().as_str() {
+            "error" => Level::Error,
+            "warn" => Level::Warn,
+            "info" => Level::Info,
+            "debug" => Level::Debug,
+            "trace" => Level::Trace,
+            _ => return None,
+        })
+    }
+
+    pub fn as_str(self) -> &'static str {
+        match self {
+            Level::Error => "ERROR",
+            Level::Warn => "WARN",
+            Level::Info => "INFO",
+            Level::Debug => "DEBUG",
+            Level::Trace => "TRACE",
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Timestamp {
+    None,
+    Nanoseconds(u64),
+    Cycles(u64),
+}
+
+/// A log record printed by klogger.
+#[derive(Debug, PartialEq, Eq)]
+pub struct Record<'a> {
+    pub timestamp: Timestamp,
+    pub level: Level,
+    pub cpu: Option<u32>,
+    pub target: &'a str,
+    /// Source location printed after the target (`format::LOCATION_FORMAT`
+    /// and `MODULE_LOCATION_FORMAT`), empty if there is none.
+    pub location: &'a str,
+    pub message: &'a str,
+}
+
+/// Remove ANSI escape sequences (`ESC [ ... letter`) from `line`.
+pub fn strip_ansi(line: &str) -> String {
+    let mut out = String::with_capacity(line.len());
+    let mut chars = line.chars();
+    while let Some(c) = chars.next() {
+        if c == '\x1b' {
+            if chars.clone().next() == Some('[') {
+                chars.find(|c| c.is_ascii_alphabetic());
+            }
+        } else {
+            out.push(c);
+        }
+    }
+    out
+}
+
+fn parse_timestamp(s: &str) -> Option<Timestamp> {
+    let s = s.trim();
+    if s.is_empty() {
+        Some(Timestamp::None)
+    } else if let Some(cycles) = s.strip_suffix(" cyc") {
+        cycles.trim().parse().ok().map(Timestamp::Cycles)
+    } else {
+        s.parse().ok().map(Timestamp::Nanoseconds)
+    }
+}
+
+/// Parse a line (without colour codes), `None` if it isn't a log record
+/// (e.g., output of `sprintln!`).
+pub fn parse(line: &str) -> Option<Record<'_>> {
+    let open = line.find('[')?;
+    let close = open + line[open..].find(']')?;
+    let level = Level::parse(line[open + 1..close].trim())?;
+    let timestamp = parse_timestamp(&line[..open])?;
+    let (cpu, rest) = match line[close + 1..].strip_prefix(" - ") {
+        Some(rest) => (None, rest),
+        None => {
+            let (cpu, rest) = line[close + 1..].strip_prefix(" cpu")?.split_once(" - ")?;
+            (Some(cpu.parse().ok()?), rest)
+        }
+    };
+    let colon = rest.find(": ")?;
+    let (target, location) = match rest[..colon].split_once(' ') {
+        Some((target, location)) => (target, location),
+        None => (&rest[..colon], ""),
+    };
+    Some(Record {
+        timestamp,
+        level,
+        cpu,
+        target,
+        location,
+        message: &rest[colon + 2..],
+    })
+}
+
+#[cfg(test)]
+mod test {
+    use super::{parse, strip_ansi, Level, Record, Timestamp};
+
+    #[test]
+    fn records() {
+        assert_eq!(
+            parse("      1234 [INFO ] - net::tcp: connected: 10.0.0.1"),
+            Some(Record {
+                timestamp: Timestamp::Nanoseconds(1234),
+                level: Level::Info,
+                cpu: None,
+                target: "net::tcp",
+                location: "",
+                message: "connected: 10.0.0.1",
+            })
+        );
+        assert_eq!(
+            parse("      1234 [INFO ] - net kernel::net src/net.rs:7: up")
+                .map(|r| (r.target, r.location, r.message)),
+            Some(("net", "kernel::net src/net.rs:7", "up"))
+        );
+        assert_eq!(
+            parse("      1234 [INFO ] cpu12 - net: up").map(|r| (r.cpu, r.target)),
+            Some((Some(12), "net"))
+        );
+        assert_eq!(
+            parse("  98765432 cyc [WARN ] - pci: bar [0] - unmapped").map(|r| r.timestamp),
+            Some(Timestamp::Cycles(98765432))
+        );
+        assert_eq!(
+            parse(" [TRACE] - a: ").map(|r| (r.timestamp, r.level)),
+            Some((Timestamp::None, Level::Trace))
+        );
+    }
+
+    #[test]
+    fn not_records() {
+        assert_eq!(parse("Booting kernel [v1.0] - hello: x"), None);
+        assert_eq!(parse("plain text"), None);
+        assert_eq!(parse("12 [INFO ] - no colon"), None);
+        assert_eq!(parse("12 [INFO ] cpux - a: b"), None);
+    }
+
+    #[test]
+    fn colours() {
+        let line =
+            "\x1b[93m      1234\x1b[39m [\x1b[38;5;136mINFO \x1b[39m] - net: \x1b[97mup\x1b[39m";
+        assert_eq!(strip_ansi(line), "      1234 [INFO ] - net: up");
+    }
+}
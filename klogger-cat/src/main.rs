@@ -0,0 +1,277 @@
+//! Filters, converts and re-colours text output of klogger.
+//!
+//! Usage: `klogger-cat [OPTIONS] [LOG]`, reads from stdin if no log file is
+//! given. Lines that aren't log records (e.g., `sprintln!` output) are
+//! passed through unless a filter is set.
+
+mod line;
+mod output;
+
+use std::env;
+use std::fs;
+use std::io::{self, BufRead, BufReader, IsTerminal, Write};
+use std::process;
+
+use line::{Level, Record, Timestamp};
+
+const USAGE: &str = "usage: klogger-cat [OPTIONS] [LOG]
+
+options:
+    --level <LEVEL>       only show records up to LEVEL (error, warn, info, debug, trace)
+    --target <PREFIX>     only show records for PREFIX and its submodules (repeatable)
+    --cpu <ID>            only show records logged on core ID (repeatable)
+    --since <TIME>        only show records at or after TIME (e.g. 1500, 20us, 3ms, 2s)
+    --until <TIME>        only show records at or before TIME
+    --freq <HZ>           convert cycle timestamps to nanoseconds
+    --color <WHEN>        always, never or auto (default)
+    --json                print records as JSON lines";
+
+#[derive(Debug, Default)]
+struct Options {
+    level: Option<Level>,
+    targets: Vec<String>,
+    cpus: Vec<u32>,
+    since: Option<u64>,
+    until: Option<u64>,
+    freq: Option<u64>,
+    color: Option<bool>,
+    json: bool,
+    input: Option<String>,
+}
+
+/// Parse a duration in nanoseconds, with an optional `ns`, `us`, `ms` or
+/// `s` suffix.
+fn parse_time(s: &str) -> Option<u64> {
+    let (number, scale) = [
+        ("ns", 1),
+        ("us", 1_000),
+        ("ms", 1_000_000),
+        ("s", 1_000_000_000),
+    ]
+    .iter()
+    .find_map(|&(suffix, scale)| s.strip_suffix(suffix).map(|n| (n, scale)))
+    .unwrap_or((s, 1));
+    number.trim().parse::<u64>().ok()?.checked_mul(scale)
+}
+
+fn parse_args<I: Iterator<Item = String>>(mut args: I) -> Result<Options, String> {
+    let mut options = Options::default();
+    while let Some(arg) = args.next() {
+        if arg == "--json" {
+            options.json = true;
+            continue;
+        }
+        if !arg.starts_with("--") {
+            if options.input.replace(arg).is_some() {
+                return Err(String::from("more than one input file"));
+            }
+            continue;
+        }
+        let value = args
+            .next()
+            .ok_or_else(|| format!("{} needs a value", arg))?;
+        let invalid = || format!("invalid value for {}: {}", arg, value);
+        match arg.as_str() {
+            "--level" => options.level = Some(Level::parse(&value).ok_or_else(invalid)?),
+            "--target" => options.targets.push(value),
+            "--cpu" => options.cpus.push(value.parse().map_err(|_| invalid())?),
+            "--since" => options.since = Some(parse_time(&value).ok_or_else(invalid)?),
+            "--until" => options.until = Some(parse_time(&value).ok_or_else(invalid)?),
+            "--freq" => match value.parse() {
+                Ok(0) | Err(_) => return Err(invalid()),
+                Ok(freq) => options.freq = Some(freq),
+            },
+            "--color" => {
+                options.color = match value.as_str() {
+                    "always" => Some(true),
+                    "never" => Some(false),
+                    "auto" => None,
+                    _ => return Err(invalid()),
+                }
+            }
+            _ => return Err(format!("unknown option {}", arg)),
+        }
+    }
+    Ok(options)
+}
+
+/// Is `target` the module `prefix` or one of its submodules?
+fn target_matches(target: &str, prefix: &str) -> bool {
+    match target.strip_prefix(prefix) {
+        Some(rest) => rest.is_empty() || rest.starts_with("::"),
+        None => false,
+    }
+}
+
+impl Options {
+    fn has_filter(&self) -> bool {
+        self.level.is_some()
+            || !self.targets.is_empty()
+            || !self.cpus.is_empty()
+            || self.since.is_some()
+            || self.until.is_some()
+    }
+
+    /// Convert cycles to nanoseconds if we know the frequency.
+    fn convert(&self, timestamp: Timestamp) -> Timestamp {
+        match (timestamp, self.freq) {
+            (Timestamp::Cycles(cycles), Some(freq)) => {
+                let ns = cycles as u128 * 1_000_000_000 / freq as u128;
+                Timestamp::Nanoseconds(ns as u64)
+            }
+            (timestamp, _) => timestamp,
+        }
+    }
+
+    fn matches(&self, record: &Record) -> bool {
+        if self.level.is_some_and(|level| record.level > level) {
+            return false;
+        }
+        if !self.targets.is_empty()
+            && !self
+                .targets
+                .iter()
+                .any(|t| target_matches(record.target, t))
+        {
+            return false;
+        }
+        if !self.cpus.is_empty() && !record.cpu.is_some_and(|cpu| self.cpus.contains(&cpu)) {
+            return false;
+        }
+        if self.since.is_none() && self.until.is_none() {
+            return true;
+        }
+        // Records without a usable timestamp are outside of any time range
+        match record.timestamp {
+            Timestamp::Nanoseconds(ns) => {
+                self.since.is_none_or(|since| ns >= since)
+                    && self.until.is_none_or(|until| ns <= until)
+            }
+            _ => false,
+        }
+    }
+
+    /// Output for `line`, or `None` if it's filtered out.
+    fn process(&self, line: &str, colored: bool) -> Option<String> {
+        let plain = line::strip_ansi(line.trim_end_matches(['\r', '\n']));
+        match line::parse(&plain) {
+            Some(mut record) => {
+                record.timestamp = self.convert(record.timestamp);
+                if !self.matches(&record) {
+                    None
+                } else if self.json {
+                    Some(output::json(&record))
+                } else {
+                    Some(output::text(&record, colored))
+                }
+            }
+            None if self.has_filter() => None,
+            None if self.json => Some(output::json_text(&plain)),
+            None => Some(plain),
+        }
+    }
+}
+
+fn run(options: &Options, input: Box<dyn BufRead>) -> Result<(), String> {
+    let colored = options.color.unwrap_or_else(|| {
+        io::stdout().is_terminal() && env::var_os("NO_COLOR").is_none_or(|v| v.is_empty())
+    });
+    let stdout = io::stdout();
+    let mut out = stdout.lock();
+    for line in input.split(b'\n') {
+        let line = line.map_err(|e| format!("can't read log: {}", e))?;
+        if let Some(line) = options.process(&String::from_utf8_lossy(&line), colored) {
+            writeln!(out, "{}", line).map_err(|e| e.to_string())?;
+        }
+    }
+    Ok(())
+}
+
+fn main() {
+    let options = match parse_args(env::args().skip(1)) {
+        Ok(options) => options,
+        Err(e) => {
+            eprintln!("klogger-cat: {}\n{}", e, USAGE);
+            process::exit(2);
+        }
+    };
+    let input: Box<dyn BufRead> = match &options.input {
+        None => Box::new(BufReader::new(io::stdin())),
+        Some(path) => match fs::File::open(path) {
+            Ok(file) => Box::new(BufReader::new(file)),
+            Err(e) => {
+                eprintln!("klogger-cat: can't open {}: {}", path, e);
+                process::exit(1);
+            }
+        },
+    };
+    if let Err(e) = run(&options, input) {
+        eprintln!("klogger-cat: {}", e);
+        process::exit(1);
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{parse_args, parse_time, Options};
+
+    fn options(args: &[&str]) -> Options {
+        parse_args(args.iter().map(|s| String::from(*s))).unwrap()
+    }
+
+    #[test]
+    fn times() {
+        assert_eq!(parse_time("1500"), Some(1500));
+        assert_eq!(parse_time("20us"), Some(20_000));
+        assert_eq!(parse_time("3ms"), Some(3_000_000));
+        assert_eq!(parse_time("2s"), Some(2_000_000_000));
+        assert_eq!(parse_time("2h"), None);
+        assert!(parse_args(["--level", "loud"].iter().map(|s| String::from(*s))).is_err());
+        assert!(parse_args(["--freq", "0"].iter().map(|s| String::from(*s))).is_err());
+    }
+
+    #[test]
+    fn filters() {
+        let opts = options(&["--level", "info", "--target", "net", "--since", "1us"]);
+        assert_eq!(
+            opts.process("      1000 [INFO ] - net::tcp: up", false),
+            Some(String::from("      1000 [INFO ] - net::tcp: up"))
+        );
+        assert_eq!(opts.process("      1000 [DEBUG] - net: up", false), None);
+        assert_eq!(
+            opts.process("      1000 [INFO ] - network: up", false),
+            None
+        );
+        assert_eq!(opts.process("       999 [INFO ] - net: up", false), None);
+        assert_eq!(opts.process("booting", false), None);
+        let opts = options(&["--cpu", "1", "--cpu", "3"]);
+        assert!(opts.process("  1 [INFO ] cpu3 - net: up", false).is_some());
+        assert_eq!(opts.process("  1 [INFO ] cpu2 - net: up", false), None);
+        assert_eq!(opts.process("  1 [INFO ] - net: up", false), None);
+        assert_eq!(
+            options(&[]).process("booting\r\n", false),
+            Some(String::from("booting"))
+        );
+    }
+
+    #[test]
+    fn cycles() {
+        let opts = options(&["--freq", "2000000000", "--until", "1us", "--json"]);
+        assert_eq!(
+            opts.process("      2000 cyc [WARN ] - pci: bar", false),
+            Some(String::from(
+                r#"{"ts_ns":1000,"level":"WARN","target":"pci","module":null,"file":null,"line":null,"cpu":null,"msg":"bar"}"#
+            ))
+        );
+        assert_eq!(
+            opts.process("      2002 cyc [WARN ] - pci: bar", false),
+            None
+        );
+        // Without a frequency cycles can't be compared to a time range
+        let opts = options(&["--since", "0"]);
+        assert_eq!(
+            opts.process("      2000 cyc [WARN ] - pci: bar", false),
+            None
+        );
+    }
+}
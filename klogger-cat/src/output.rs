@@ -0,0 +1,211 @@
+//! Writes records as (coloured) text or JSON.
+
+use std::fmt::Write;
+
+use line::{Level, Record, Timestamp};
+
+/// Same colours as klogger's default theme (`Theme::DARK`).
+fn level_color(level: Level) -> &'static str {
+    match level {
+        Level::Error => "\x1b[38;5;202m",
+        Level::Warn => "\x1b[38;5;167m",
+        Level::Info => "\x1b[38;5;136m",
+        Level::Debug => "\x1b[38;5;64m",
+        Level::Trace => "\x1b[38;5;32m",
+    }
+}
+
+const TIMESTAMP_COLOR: &str = "\x1b[93m";
+const MESSAGE_COLOR: &str = "\x1b[97m";
+const RESET: &str = "\x1b[39m";
+
+/// Write `record` in klogger's default layout (with the source location
+/// if it had one).
+pub fn text(record: &Record, colored: bool) -> String {
+    let paint = |color: &str, s: &str| {
+        if colored {
+            format!("{}{}{}", color, s, RESET)
+        } else {
+            String::from(s)
+        }
+    };
+    let timestamp = match record.timestamp {
+        Timestamp::None => String::new(),
+        Timestamp::Nanoseconds(ns) => format!("{:>10}", ns),
+        Timestamp::Cycles(cycles) => format!("{:>10} cyc", cycles),
+    };
+    let cpu = match record.cpu {
+        Some(cpu) => format!(" cpu{}", cpu),
+        None => String::new(),
+    };
+    let location = if record.location.is_empty() {
+        String::new()
+    } else {
+        format!(" {}", record.location)
+    };
+    format!(
+        "{} [{}]{} - {}{}: {}",
+        paint(TIMESTAMP_COLOR, &timestamp),
+        paint(
+            level_color(record.level),
+            &format!("{:5}", record.level.as_str())
+        ),
+        cpu,
+        record.target,
+        location,
+        paint(MESSAGE_COLOR, record.message)
+    )
+}
+
+fn json_string(out: &mut String, s: &str) {
+    out.push('"');
+    for c in s.chars() {
+        match c {
+            '"' => out.push_str("\\\""),
+            '\\' => out.push_str("\\\\"),
+            '\n' => out.push_str("\\n"),
+            '\r' => out.push_str("\\r"),
+            '\t' => out.push_str("\\t"),
+            c if (c as u32) < 0x20 => {
+                let _ = write!(out, "\\u{:04x}", c as u32);
+            }
+            c => out.push(c),
+        }
+    }
+    out.push('"');
+}
+
+/// Write `value` or `null`.
+fn json_optional_string(out: &mut String, value: Option<&str>) {
+    match value {
+        Some(value) => json_string(out, value),
+        None => out.push_str("null"),
+    }
+}
+
+/// Split a location printed by klogger (`file:line` or `module file:line`)
+/// into module, file and line.
+fn split_location(location: &str) -> (Option<&str>, Option<&str>, Option<u32>) {
+    if location.is_empty() {
+        return (None, None, None);
+    }
+    let (module, file) = match location.split_once(' ') {
+        Some((module, file)) => (Some(module), file),
+        None => (None, location),
+    };
+    match file.rsplit_once(':') {
+        Some((file, line)) if line.parse::<u32>().is_ok() => {
+            (module, Some(file), line.parse().ok())
+        }
+        _ => (module, Some(file), None),
+    }
+}
+
+/// Write `record` as a JSON object, with the same fields klogger uses
+/// (`null` for those the text line doesn't have).
+pub fn json(record: &Record) -> String {
+    let mut out = String::from("{");
+    match record.timestamp {
+        Timestamp::None => out.push_str("\"ts_ns\":null"),
+        Timestamp::Nanoseconds(ns) => {
+            let _ = write!(out, "\"ts_ns\":{}", ns);
+        }
+        Timestamp::Cycles(cycles) => {
+            let _ = write!(out, "\"ts_cycles\":{}", cycles);
+        }
+    }
+    let _ = write!(out, ",\"level\":\"{}\"", record.level.as_str());
+    out.push_str(",\"target\":");
+    json_string(&mut out, record.target);
+    let (module, file, line) = split_location(record.location);
+    out.push_str(",\"module\":");
+    json_optional_string(&mut out, module);
+    out.push_str(",\"file\":");
+    json_optional_string(&mut out, file);
+    match line {
+        Some(line) => {
+            let _ = write!(out, ",\"line\":{}", line);
+        }
+        None => out.push_str(",\"line\":null"),
+    }
+    match record.cpu {
+        Some(cpu) => {
+            let _ = write!(out, ",\"cpu\":{}", cpu);
+        }
+        None => out.push_str(",\"cpu\":null"),
+    }
+    out.push_str(",\"msg\":");
+    json_string(&mut out, record.message);
+    out.push('}');
+    out
+}
+
+/// A line that isn't a log record as JSON.
+pub fn json_text(text: &str) -> String {
+    let mut out = String::from(
+        "{\"ts_ns\":null,\"level\":null,\"target\":null,\"module\":null,\"file\":null,\
+         \"line\":null,\"cpu\":null,\"msg\":",
+    );
+    json_string(&mut out, text);
+    out.push('}');
+    out
+}
+
+#[cfg(test)]
+mod test {
+    use super::{json, json_text, text};
+    use line::{Level, Record, Timestamp};
+
+    fn record() -> Record<'static> {
+        Record {
+            timestamp: Timestamp::Nanoseconds(42),
+            level: Level::Warn,
+            cpu: None,
+            target: "net",
+            location: "",
+            message: "say \"hi\"",
+        }
+    }
+
+    #[test]
+    fn plain_and_colored() {
+        assert_eq!(
+            text(&record(), false),
+            "        42 [WARN ] - net: say \"hi\""
+        );
+        assert_eq!(
+            text(&record(), true),
+            "\x1b[93m        42\x1b[39m [\x1b[38;5;167mWARN \x1b[39m] - net: \x1b[97msay \"hi\"\x1b[39m"
+        );
+    }
+
+    #[test]
+    fn as_json() {
+        assert_eq!(
+            json(&record()),
+            r#"{"ts_ns":42,"level":"WARN","target":"net","module":null,"file":null,"line":null,"cpu":null,"msg":"say \"hi\""}"#
+        );
+        let located = Record {
+            cpu: Some(1),
+            location: "src/net.rs:7",
+            ..record()
+        };
+        assert_eq!(
+            json(&located),
+            r#"{"ts_ns":42,"level":"WARN","target":"net","module":null,"file":"src/net.rs","line":7,"cpu":1,"msg":"say \"hi\""}"#
+        );
+        assert_eq!(
+            text(&located, false),
+            "        42 [WARN ] cpu1 - net src/net.rs:7: say \"hi\""
+        );
+        let with_module = Record {
+            location: "net::tcp src/net.rs:7",
+            ..record()
+        };
+        assert!(json(&with_module).contains(r#""module":"net::tcp","file":"src/net.rs","line":7,"#));
+        assert_eq!(
+            json_text("a\tb"),
+            r#"{"ts_ns":null,"level":null,"target":null,"module":null,"file":null,"line":null,"cpu":null,"msg":"a\tb"}"#
+        );
+    }
+}
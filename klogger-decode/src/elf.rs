@@ -0,0 +1,158 @@
+//! Finds the interned format strings in an ELF file.
+
+use std::convert::{TryFrom, TryInto};
+
+/// Marks the start of the interned strings (see klogger's `binary` module).
+const ANCHOR: &[u8] = b"KLOGGER_FMT_V1\0";
+
+/// The section `binlog!` puts its format strings in.
+const SECTION: &str = "klogger_fmt";
+
+/// Content of the `klogger_fmt` section.
+#[derive(Debug)]
+pub struct Strings {
+    data: Vec<u8>,
+    /// Offset of the anchor in `data`.
+    anchor: usize,
+}
+
+fn u16_at(data: &[u8], off: usize) -> Option<u16> {
+    Some(u16::from_le_bytes(data.get(off..off + 2)?.try_into().ok()?))
+}
+
+fn u32_at(data: &[u8], off: usize) -> Option<u32> {
+    Some(u32::from_le_bytes(data.get(off..off + 4)?.try_into().ok()?))
+}
+
+fn u64_at(data: &[u8], off: usize) -> Option<u64> {
+    Some(u64::from_le_bytes(data.get(off..off + 8)?.try_into().ok()?))
+}
+
+/// Nul-terminated string at the start of `data`.
+fn c_str(data: &[u8]) -> Option<&str> {
+    let len = data.iter().position(|&b| b == 0)?;
+    std::str::from_utf8(&data[..len]).ok()
+}
+
+/// Content of the section called `name` (only 64-bit little-endian ELF
+/// files are supported).
+fn section<'a>(elf: &'a [u8], name: &str) -> Result<&'a [u8], String> {
+    if elf.get(..4) != Some(b"\x7fELF") {
+        return Err(String::from("not an ELF file"));
+    }
+    if elf.get(4..6) != Some(&[2, 1]) {
+        return Err(String::from(
+            "only 64-bit little-endian ELF files are supported",
+        ));
+    }
+    let malformed = || String::from("malformed ELF file");
+    let shoff = u64_at(elf, 0x28).ok_or_else(malformed)? as usize;
+    let shentsize = u16_at(elf, 0x3a).ok_or_else(malformed)? as usize;
+    let shnum = u16_at(elf, 0x3c).ok_or_else(malformed)? as usize;
+    let shstrndx = u16_at(elf, 0x3e).ok_or_else(malformed)? as usize;
+
+    let header = |i: usize| -> Option<(u32, &'a [u8])> {
+        let sh = shoff + i * shentsize;
+        let name = u32_at(elf, sh)?;
+        let offset = u64_at(elf, sh + 0x18)? as usize;
+        let size = u64_at(elf, sh + 0x20)? as usize;
+        Some((name, elf.get(offset..offset.checked_add(size)?)?))
+    };
+    let (_, names) = header(shstrndx).ok_or_else(malformed)?;
+    for i in 0..shnum {
+        let (name_off, data) = header(i).ok_or_else(malformed)?;
+        if names.get(name_off as usize..).and_then(c_str) == Some(name) {
+            return Ok(data);
+        }
+    }
+    Err(format!(
+        "no {} section (is the `binary` feature enabled?)",
+        name
+    ))
+}
+
+impl Strings {
+    /// Read the interned strings from the ELF file `elf`.
+    pub fn from_elf(elf: &[u8]) -> Result<Strings, String> {
+        let data = section(elf, SECTION)?;
+        let anchor = data
+            .windows(ANCHOR.len())
+            .position(|w| w == ANCHOR)
+            .ok_or_else(|| format!("no anchor in {} section", SECTION))?;
+        Ok(Strings {
+            data: data.to_vec(),
+            anchor,
+        })
+    }
+
+    /// No interned strings, for records that don't need any.
+    #[cfg(test)]
+    pub fn empty() -> Strings {
+        Strings {
+            data: Vec::new(),
+            anchor: 0,
+        }
+    }
+
+    /// Module path and format string of the entry `id`.
+    pub fn get(&self, id: i64) -> Option<(&str, &str)> {
+        let start = (self.anchor as i64).checked_add(id)?;
+        let entry = self.data.get(usize::try_from(start).ok()?..)?;
+        let module = c_str(entry)?;
+        let format = c_str(&entry[module.len() + 1..])?;
+        Some((module, format))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{Strings, ANCHOR};
+
+    /// A minimal ELF file with a `klogger_fmt` section containing `content`.
+    fn elf(content: &[u8]) -> Vec<u8> {
+        let names = b"\0klogger_fmt\0.shstrtab\0";
+        let mut elf = vec![0; 64];
+        elf[..6].copy_from_slice(b"\x7fELF\x02\x01");
+        let content_off = elf.len();
+        elf.extend_from_slice(content);
+        let names_off = elf.len();
+        elf.extend_from_slice(names);
+
+        let shoff = elf.len();
+        elf[0x28..0x30].copy_from_slice(&(shoff as u64).to_le_bytes());
+        elf[0x3a..0x3c].copy_from_slice(&64u16.to_le_bytes());
+        elf[0x3c..0x3e].copy_from_slice(&3u16.to_le_bytes());
+        elf[0x3e..0x40].copy_from_slice(&2u16.to_le_bytes());
+        for &(name, off, size) in &[
+            (0u32, 0usize, 0usize),
+            (1, content_off, content.len()),
+            (13, names_off, names.len()),
+        ] {
+            let mut sh = [0u8; 64];
+            sh[..4].copy_from_slice(&name.to_le_bytes());
+            sh[0x18..0x20].copy_from_slice(&(off as u64).to_le_bytes());
+            sh[0x20..0x28].copy_from_slice(&(size as u64).to_le_bytes());
+            elf.extend_from_slice(&sh);
+        }
+        elf
+    }
+
+    #[test]
+    fn lookup() {
+        let mut content = b"net\0up {}\0".to_vec();
+        content.extend_from_slice(ANCHOR);
+        content.extend_from_slice(b"pci\0bar {:#x}\0");
+        let strings = Strings::from_elf(&elf(&content)).unwrap();
+
+        assert_eq!(strings.get(-10), Some(("net", "up {}")));
+        assert_eq!(strings.get(ANCHOR.len() as i64), Some(("pci", "bar {:#x}")));
+        assert_eq!(strings.get(1000), None);
+        assert_eq!(strings.get(-1000), None);
+    }
+
+    #[test]
+    fn errors() {
+        assert!(Strings::from_elf(b"not an elf").is_err());
+        assert!(Strings::from_elf(&elf(b"no anchor")).is_err());
+    }
+}
@@ -0,0 +1,120 @@
+//! Turns binary klogger output (see `klogger::binary`) back into text.
+//!
+//! Usage: `klogger-decode <ELF> [LOG]`, reads from stdin if no log file is
+//! given. `ELF` is the binary that wrote the log, we need it for the
+//! interned format strings.
+
+mod elf;
+mod render;
+mod wire;
+
+use std::env;
+use std::fs;
+use std::io::{self, BufRead, BufReader, Write};
+use std::process;
+
+use elf::Strings;
+use wire::{Body, Frame, Timestamp};
+
+const LEVELS: [&str; 6] = ["", "ERROR", "WARN", "INFO", "DEBUG", "TRACE"];
+
+/// Format `frame` like klogger's default text layout.
+fn line(strings: &Strings, frame: &Frame) -> String {
+    let timestamp = match frame.timestamp {
+        Timestamp::None => String::new(),
+        Timestamp::Nanoseconds(ns) => format!("{:>10}", ns),
+        Timestamp::Cycles(cycles) => format!("{:>10} cyc", cycles),
+    };
+    let (target, message) = match &frame.body {
+        Body::Dynamic { target, message } => (target.clone(), message.clone()),
+        Body::Interned { id, args } => match strings.get(*id) {
+            Some((module, format)) => (String::from(module), render::render(format, args)),
+            None => (
+                String::from("?"),
+                format!("<unknown format string {}> {:?}", id, args),
+            ),
+        },
+    };
+    format!(
+        "{} [{:5}] - {}: {}",
+        timestamp, LEVELS[frame.level as usize], target, message
+    )
+}
+
+/// Decode a chunk of input between two 0 bytes.
+///
+/// Anything klogger doesn't write as a frame (e.g., `sprintln!`) ends up in
+/// front of the next frame, we print that as text.
+fn decode(strings: &Strings, chunk: &[u8]) -> String {
+    let frame = |bytes: &[u8]| {
+        wire::cobs_decode(bytes)
+            .and_then(|payload| wire::parse(&payload))
+            .map(|frame| line(strings, &frame))
+    };
+    if let Ok(line) = frame(chunk) {
+        return line;
+    }
+    // The frame starts after one of the line breaks (not necessarily the
+    // last one, frames can contain '\n' bytes as well)
+    for (pos, _) in chunk.iter().enumerate().filter(|&(_, &b)| b == b'\n') {
+        if let Ok(line) = frame(&chunk[pos + 1..]) {
+            let text = String::from_utf8_lossy(&chunk[..pos]);
+            return format!("{}\n{}", text.trim_end(), line);
+        }
+    }
+    String::from_utf8_lossy(chunk).trim_end().to_string()
+}
+
+fn run(elf: &str, input: Box<dyn BufRead>) -> Result<(), String> {
+    let elf = fs::read(elf).map_err(|e| format!("can't read {}: {}", elf, e))?;
+    let strings = Strings::from_elf(&elf)?;
+
+    let stdout = io::stdout();
+    let mut out = stdout.lock();
+    for chunk in input.split(0) {
+        let chunk = chunk.map_err(|e| format!("can't read log: {}", e))?;
+        if chunk.is_empty() {
+            continue;
+        }
+        writeln!(out, "{}", decode(&strings, &chunk)).map_err(|e| e.to_string())?;
+    }
+    Ok(())
+}
+
+fn main() {
+    let args: Vec<String> = env::args().collect();
+    let input: Box<dyn BufRead> = match args.len() {
+        2 => Box::new(BufReader::new(io::stdin())),
+        3 => match fs::File::open(&args[2]) {
+            Ok(file) => Box::new(BufReader::new(file)),
+            Err(e) => {
+                eprintln!("klogger-decode: can't open {}: {}", args[2], e);
+                process::exit(1);
+            }
+        },
+        _ => {
+            eprintln!("usage: klogger-decode <ELF> [LOG]");
+            process::exit(2);
+        }
+    };
+    if let Err(e) = run(&args[1], input) {
+        eprintln!("klogger-decode: {}", e);
+        process::exit(1);
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::decode;
+    use elf::Strings;
+
+    #[test]
+    fn text_before_frame() {
+        let strings = Strings::empty();
+        // "boot\r\n" followed by a dynamic record whose COBS code is '\n'
+        let mut chunk = b"boot\r\n".to_vec();
+        chunk.extend_from_slice(&[0x0a, 0x23, 4, 2, b'n', b'e', 4, 2, b'u', b'p']);
+        assert_eq!(decode(&strings, &chunk), "boot\n [INFO ] - ne: up");
+        assert_eq!(decode(&strings, b"just text\r\n"), "just text");
+    }
+}
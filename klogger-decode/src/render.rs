@@ -0,0 +1,172 @@
+//! Formats interned format strings with the decoded arguments.
+//!
+//! Supports what `binlog!` allows: `{}`, `{:?}` and the integer formats
+//! `x`, `X`, `b` and `o`, each with an optional `#`, `0`, alignment and
+//! width.
+
+use wire::Arg;
+
+/// A parsed `{:...}` spec.
+#[derive(Default)]
+struct Spec {
+    align: Option<char>,
+    alternate: bool,
+    zero: bool,
+    width: usize,
+    ty: Option<char>,
+}
+
+impl Spec {
+    fn parse(spec: &str) -> Option<Spec> {
+        let mut parsed = Spec::default();
+        let mut chars = spec.chars().peekable();
+        if let Some(&c) = chars.peek() {
+            if c == '<' || c == '>' || c == '^' {
+                parsed.align = Some(c);
+                chars.next();
+            }
+        }
+        if chars.peek() == Some(&'#') {
+            parsed.alternate = true;
+            chars.next();
+        }
+        if chars.peek() == Some(&'0') {
+            parsed.zero = true;
+            chars.next();
+        }
+        while let Some(digit) = chars.peek().and_then(|c| c.to_digit(10)) {
+            parsed.width = parsed.width * 10 + digit as usize;
+            chars.next();
+        }
+        parsed.ty = chars.next();
+        match (parsed.ty, chars.next()) {
+            (None, None) | (Some('?' | 'x' | 'X' | 'b' | 'o'), None) => Some(parsed),
+            _ => None,
+        }
+    }
+
+    fn format(&self, arg: &Arg) -> String {
+        let (prefix, digits) = match (self.ty, arg) {
+            (Some(ty @ ('x' | 'X' | 'b' | 'o')), Arg::Unsigned(n)) => radix(ty, *n),
+            // Like Rust, negative numbers are printed as two's complement
+            // of their size
+            (Some(ty @ ('x' | 'X' | 'b' | 'o')), Arg::Signed(n, size)) => {
+                let bits = size * 8;
+                radix(ty, *n as u64 & (u64::MAX >> (64 - bits.min(64))))
+            }
+            (Some('?'), Arg::Str(s)) => ("", format!("{:?}", s)),
+            (Some('?'), Arg::Char(c)) => ("", format!("{:?}", c)),
+            (Some('?'), Arg::Float(f)) => ("", format!("{:?}", f)),
+            (_, Arg::Unsigned(n)) => ("", n.to_string()),
+            (_, Arg::Signed(n, _)) => ("", n.to_string()),
+            (_, Arg::Bool(b)) => ("", b.to_string()),
+            (_, Arg::Char(c)) => ("", c.to_string()),
+            (_, Arg::Str(s)) => ("", s.clone()),
+            (_, Arg::Float(f)) => ("", f.to_string()),
+        };
+        let prefix = if self.alternate { prefix } else { "" };
+
+        let len = prefix.len() + digits.chars().count();
+        let pad = self.width.saturating_sub(len);
+        if self.zero && self.align.is_none() {
+            return format!("{}{}{}", prefix, "0".repeat(pad), digits);
+        }
+        let numeric = matches!(arg, Arg::Unsigned(_) | Arg::Signed(..) | Arg::Float(_));
+        let (left, right) = match self.align {
+            Some('<') => (0, pad),
+            Some('^') => (pad / 2, pad - pad / 2),
+            Some(_) => (pad, 0),
+            None if numeric => (pad, 0),
+            None => (0, pad),
+        };
+        format!(
+            "{}{}{}{}",
+            " ".repeat(left),
+            prefix,
+            digits,
+            " ".repeat(right)
+        )
+    }
+}
+
+fn radix(ty: char, n: u64) -> (&'static str, String) {
+    match ty {
+        'x' => ("0x", format!("{:x}", n)),
+        'X' => ("0x", format!("{:X}", n)),
+        'b' => ("0b", format!("{:b}", n)),
+        _ => ("0o", format!("{:o}", n)),
+    }
+}
+
+/// Replace the placeholders of `format` with `args`.
+///
+/// Placeholders we can't handle (or that have no argument) are printed as
+/// `{?}`.
+pub fn render(format: &str, args: &[Arg]) -> String {
+    let mut out = String::new();
+    let mut args = args.iter();
+    let mut rest = format;
+    while let Some(pos) = rest.find(['{', '}']) {
+        out.push_str(&rest[..pos]);
+        let tail = &rest[pos..];
+        if tail.starts_with("{{") || tail.starts_with("}}") {
+            out.push_str(&tail[..1]);
+            rest = &tail[2..];
+            continue;
+        }
+        let end = match (tail.starts_with('{'), tail.find('}')) {
+            (true, Some(end)) => end,
+            _ => {
+                // Unbalanced, can't happen for strings accepted by `format_args!`
+                out.push_str(tail);
+                return out;
+            }
+        };
+        let spec = tail[1..end]
+            .strip_prefix(':')
+            .or(Some(&tail[1..end]).filter(|s| s.is_empty()));
+        match (spec.and_then(Spec::parse), args.next()) {
+            (Some(spec), Some(arg)) => out.push_str(&spec.format(arg)),
+            _ => out.push_str("{?}"),
+        }
+        rest = &tail[end + 1..];
+    }
+    out.push_str(rest);
+    out
+}
+
+#[cfg(test)]
+mod test {
+    use super::render;
+    use wire::Arg;
+
+    #[test]
+    fn placeholders() {
+        let args = [
+            Arg::Str(String::from("eth0")),
+            Arg::Unsigned(255),
+            Arg::Signed(-3, 4),
+            Arg::Bool(true),
+        ];
+        assert_eq!(render("{} irq {:#x} {} {}", &args), "eth0 irq 0xff -3 true");
+        assert_eq!(render("{{{:?}}}", &args), "{\"eth0\"}");
+        assert_eq!(render("no args", &args), "no args");
+        assert_eq!(render("{} {} {} {} {}", &args), "eth0 255 -3 true {?}");
+        assert_eq!(render("{0}", &args), "{?}");
+    }
+
+    #[test]
+    fn widths() {
+        let n = [Arg::Unsigned(10)];
+        assert_eq!(render("{:5}", &n), "   10");
+        assert_eq!(render("{:<5}|", &n), "10   |");
+        assert_eq!(render("{:05}", &n), "00010");
+        assert_eq!(render("{:#06x}", &n), "0x000a");
+        assert_eq!(render("{:08b}", &n), "00001010");
+        assert_eq!(render("{:X}", &[Arg::Signed(-1, 8)]), "FFFFFFFFFFFFFFFF");
+        assert_eq!(render("{:x}", &[Arg::Signed(-1, 1)]), "ff");
+        assert_eq!(render("{:#b}", &[Arg::Signed(-2, 2)]), "0b1111111111111110");
+        assert_eq!(render("{:x}", &[Arg::Signed(5, 4)]), "5");
+        assert_eq!(render("{:4}|", &[Arg::Str(String::from("a"))]), "a   |");
+    }
+}
@@ -0,0 +1,233 @@
+//! Decoding of the frames written by klogger's `binary` module.
+
+use std::str;
+
+const TAG_UNSIGNED: u8 = 0;
+const TAG_SIGNED: u8 = 1;
+const TAG_BOOL: u8 = 2;
+const TAG_CHAR: u8 = 3;
+const TAG_STR: u8 = 4;
+const TAG_F64: u8 = 5;
+
+/// Header bit for records without interned format string.
+const DYNAMIC: u8 = 1 << 5;
+
+pub type Error = &'static str;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Timestamp {
+    None,
+    Nanoseconds(u64),
+    Cycles(u64),
+}
+
+/// An argument of a `binlog!` record.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Arg {
+    Unsigned(u64),
+    /// The value and the size of the integer in bytes.
+    Signed(i64, u32),
+    Bool(bool),
+    Char(char),
+    Str(String),
+    Float(f64),
+}
+
+#[derive(Debug, PartialEq)]
+pub enum Body {
+    /// Logged with `binlog!`, `id` refers to the interned format string.
+    Interned { id: i64, args: Vec<Arg> },
+    /// Logged with the `log` crate, formatted on the target.
+    Dynamic { target: String, message: String },
+}
+
+#[derive(Debug, PartialEq)]
+pub struct Frame {
+    /// 1 (error) to 5 (trace).
+    pub level: u8,
+    pub timestamp: Timestamp,
+    pub body: Body,
+}
+
+/// Undo the COBS encoding of a frame (without the 0 delimiter).
+pub fn cobs_decode(encoded: &[u8]) -> Result<Vec<u8>, Error> {
+    let mut decoded = Vec::with_capacity(encoded.len());
+    let mut rest = encoded;
+    while let Some((&code, tail)) = rest.split_first() {
+        if code == 0 || code as usize - 1 > tail.len() {
+            return Err("invalid COBS block");
+        }
+        let len = code as usize - 1;
+        decoded.extend_from_slice(&tail[..len]);
+        rest = &tail[len..];
+        if code != 0xff && !rest.is_empty() {
+            decoded.push(0);
+        }
+    }
+    Ok(decoded)
+}
+
+struct Reader<'a>(&'a [u8]);
+
+impl<'a> Reader<'a> {
+    fn byte(&mut self) -> Result<u8, Error> {
+        let (&b, rest) = self.0.split_first().ok_or("frame too short")?;
+        self.0 = rest;
+        Ok(b)
+    }
+
+    fn bytes(&mut self, len: usize) -> Result<&'a [u8], Error> {
+        if len > self.0.len() {
+            return Err("frame too short");
+        }
+        let (bytes, rest) = self.0.split_at(len);
+        self.0 = rest;
+        Ok(bytes)
+    }
+
+    fn varint(&mut self) -> Result<u64, Error> {
+        let mut value = 0u64;
+        for shift in (0..64).step_by(7) {
+            let b = self.byte()?;
+            value |= ((b & 0x7f) as u64) << shift;
+            if b & 0x80 == 0 {
+                return Ok(value);
+            }
+        }
+        Err("varint too long")
+    }
+
+    fn zigzag(&mut self) -> Result<i64, Error> {
+        let value = self.varint()?;
+        Ok((value >> 1) as i64 ^ -((value & 1) as i64))
+    }
+
+    fn str(&mut self) -> Result<String, Error> {
+        let len = self.varint()? as usize;
+        let bytes = self.bytes(len)?;
+        str::from_utf8(bytes)
+            .map(String::from)
+            .map_err(|_| "string is not UTF-8")
+    }
+
+    fn arg(&mut self) -> Result<Arg, Error> {
+        let tag = self.byte()?;
+        Ok(match tag & 0xf {
+            TAG_UNSIGNED => Arg::Unsigned(self.varint()?),
+            TAG_SIGNED => Arg::Signed(self.zigzag()?, 1 << (tag >> 4)),
+            TAG_BOOL => Arg::Bool(self.byte()? != 0),
+            TAG_CHAR => {
+                Arg::Char(std::char::from_u32(self.varint()? as u32).ok_or("invalid char")?)
+            }
+            TAG_STR => Arg::Str(self.str()?),
+            TAG_F64 => {
+                let mut bytes = [0; 8];
+                bytes.copy_from_slice(self.bytes(8)?);
+                Arg::Float(f64::from_le_bytes(bytes))
+            }
+            _ => return Err("unknown argument type"),
+        })
+    }
+}
+
+/// Parse a decoded frame.
+pub fn parse(payload: &[u8]) -> Result<Frame, Error> {
+    let mut r = Reader(payload);
+    let header = r.byte()?;
+    let level = header & 0x7;
+    if level == 0 || level > 5 {
+        return Err("invalid level");
+    }
+    let timestamp = match (header >> 3) & 0x3 {
+        0 => Timestamp::None,
+        1 => Timestamp::Nanoseconds(r.varint()?),
+        2 => Timestamp::Cycles(r.varint()?),
+        _ => return Err("invalid timestamp kind"),
+    };
+
+    let body = if header & DYNAMIC != 0 {
+        let target = r.arg()?;
+        let message = r.arg()?;
+        match (target, message) {
+            (Arg::Str(target), Arg::Str(message)) => Body::Dynamic { target, message },
+            _ => return Err("invalid dynamic record"),
+        }
+    } else {
+        let id = r.zigzag()?;
+        let mut args = Vec::new();
+        while !r.0.is_empty() {
+            args.push(r.arg()?);
+        }
+        Body::Interned { id, args }
+    };
+
+    Ok(Frame {
+        level,
+        timestamp,
+        body,
+    })
+}
+
+#[cfg(test)]
+mod test {
+    use super::{cobs_decode, parse, Arg, Body, Frame, Timestamp};
+
+    #[test]
+    fn cobs() {
+        assert_eq!(cobs_decode(&[1]).unwrap(), b"");
+        assert_eq!(cobs_decode(&[1, 1]).unwrap(), b"\0");
+        assert_eq!(cobs_decode(&[3, 1, 2, 2, 3]).unwrap(), b"\x01\x02\0\x03");
+        assert!(cobs_decode(&[5, 1]).is_err());
+        assert!(cobs_decode(&[2, 1, 0]).is_err());
+
+        let mut long = vec![255];
+        long.extend_from_slice(&[b'x'; 254]);
+        long.extend_from_slice(&[2, b'y']);
+        let decoded = cobs_decode(&long).unwrap();
+        assert_eq!(decoded.len(), 255);
+        assert!(!decoded.contains(&0));
+    }
+
+    #[test]
+    fn interned_record() {
+        // Same frame as in klogger's `binary::test::frame_layout`
+        let payload = cobs_decode(&[
+            5, 0x0a, 0xac, 0x02, 0x03, 1, 9, 0x21, 1, 4, 2, b'h', b'i', 2, 1,
+        ])
+        .unwrap();
+        assert_eq!(
+            parse(&payload).unwrap(),
+            Frame {
+                level: 2,
+                timestamp: Timestamp::Nanoseconds(300),
+                body: Body::Interned {
+                    id: -2,
+                    args: vec![
+                        Arg::Unsigned(0),
+                        Arg::Signed(-1, 4),
+                        Arg::Str(String::from("hi")),
+                        Arg::Bool(true)
+                    ],
+                },
+            }
+        );
+    }
+
+    #[test]
+    fn dynamic_record() {
+        let payload = [0x23, 4, 3, b'n', b'e', b't', 4, 2, b'u', b'p'];
+        assert_eq!(
+            parse(&payload).unwrap(),
+            Frame {
+                level: 3,
+                timestamp: Timestamp::None,
+                body: Body::Dynamic {
+                    target: String::from("net"),
+                    message: String::from("up"),
+                },
+            }
+        );
+        assert!(parse(&[0x23, 4, 3]).is_err());
+        assert!(parse(&[0x07]).is_err());
+    }
+}
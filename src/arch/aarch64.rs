@@ -1,6 +1,8 @@
-use core::fmt::Write;
+use core::arch::asm;
 use core::sync::atomic::{AtomicU64, Ordering};
 
+use sink::Sink;
+
 pub static SERIAL_PRINT_PORT: AtomicU64 = AtomicU64::new(0xffff_0000_0900_0000);
 
 pl011_drv::create_uart!(
@@ -8,41 +10,109 @@ pl011_drv::create_uart!(
     struct UartAarch64,
     UartAarch64_TAKEN, 0xffff_0000_0900_0000);
 
-/// Write a string to the output channel.
-pub unsafe fn puts(s: &str) {
-    let mut uart = pl011_drv::PL011::new(UartAarch64::steal());
-    uart.write_str(s).unwrap();
-}
+/// The PL011 UART.
+pub struct Console;
 
-pub unsafe fn putc(c: char) {
-    let mut uart = pl011_drv::PL011::new(UartAarch64::steal());
-    uart.write_char(c).unwrap();
+impl Sink for Console {
+    fn write(&self, bytes: &[u8]) {
+        for b in bytes {
+            unsafe { putb(*b) };
+        }
+    }
 }
 
 /// Write a single byte to the output channel.
-unsafe fn putb(port: u16, b: u8) {
+unsafe fn putb(b: u8) {
     let mut uart = pl011_drv::PL011::new(UartAarch64::steal());
     uart.write_byte(b);
 }
 
 pub fn set_output(port: u64) {}
 
+pub fn set_hpet_base(_base: u64) {}
+
+/// We can't tell what is on the other end of the UART, assume a terminal.
+pub fn supports_color() -> bool {
+    true
+}
+
+/// Affinity fields of MPIDR_EL1 as Aff3.Aff2.Aff1.Aff0 (one byte each).
+pub fn cpu_id() -> u32 {
+    let mpidr: u64;
+    unsafe {
+        asm!("mrs {}, mpidr_el1", out(reg) mpidr, options(nomem, nostack));
+    }
+    (((mpidr >> 32) & 0xff) << 24 | (mpidr & 0xff_ffff)) as u32
+}
+
+/// DAIF before `disable_interrupts`.
+pub type InterruptState = u64;
+
+/// Mask IRQs and FIQs, returns the previous DAIF.
+pub fn disable_interrupts() -> InterruptState {
+    let daif: u64;
+    // No `nomem`, the compiler must not move memory accesses out of the
+    // critical section
+    unsafe {
+        asm!("mrs {}, daif", "msr daifset, #0b0011", out(reg) daif, options(nostack));
+    }
+    daif
+}
+
+/// Restore the DAIF from before `disable_interrupts`.
+pub fn restore_interrupts(daif: InterruptState) {
+    unsafe {
+        asm!("msr daif, {}", in(reg) daif, options(nostack));
+    }
+}
+
+/// Stop this core for good.
+#[cfg(feature = "panic")]
+pub fn halt() -> ! {
+    loop {
+        unsafe {
+            asm!("msr daifset, #0b0011", "wfi", options(nomem, nostack));
+        }
+    }
+}
+
+/// Read the virtual count of the ARMv8 generic timer (CNTVCT_EL0).
 pub fn get_timestamp() -> u64 {
-    0
+    let cnt: u64;
+    unsafe {
+        // The isb makes sure we don't read the counter speculatively
+        asm!("isb", "mrs {}, cntvct_el0", out(reg) cnt, options(nomem, nostack));
+    }
+    cnt
 }
 
+/// The generic timer is mandatory in ARMv8.
 pub fn has_tsc() -> bool {
-    false
+    true
 }
 
+/// The generic timer counts at a constant rate by definition.
 pub fn has_invariant_tsc() -> bool {
-    false
+    true
 }
 
+/// Frequency of the generic timer as programmed by the firmware (CNTFRQ_EL0).
 pub fn get_tsc_frequency_hz() -> Option<u64> {
-    None
+    let frq: u64;
+    unsafe {
+        asm!("mrs {}, cntfrq_el0", out(reg) frq, options(nomem, nostack));
+    }
+    // Only the lower 32 bits are defined
+    match frq & 0xffff_ffff {
+        0 => None,
+        hz => Some(hz),
+    }
 }
 
 pub fn get_vmm_tsc_frequency_hz() -> Option<u64> {
     None
 }
+
+pub fn calibrate_tsc_frequency_hz() -> Option<u64> {
+    None
+}
@@ -1,32 +1,123 @@
-/// Write a string to the output channel.
-pub unsafe fn puts(s: &str) {
-    print!("{}", s);
-}
+use std::cell::Cell;
+use std::env;
+use std::ffi::OsString;
+use std::io::{IsTerminal, Write};
+use std::sync::atomic::{AtomicU32, Ordering};
+use std::sync::OnceLock;
+use std::time::Instant;
+
+use lock::UNKNOWN_CPU;
+use sink::Sink;
+use time::NS_PER_SEC;
+
+/// Reference point for `get_timestamp`.
+static EPOCH: OnceLock<Instant> = OnceLock::new();
+
+/// Writes to stdout.
+pub struct Console;
 
-pub unsafe fn putc(c: char) {
-    print!("{}", c);
+impl Sink for Console {
+    fn write(&self, bytes: &[u8]) {
+        match std::str::from_utf8(bytes) {
+            Ok(s) => print!("{}", s),
+            // Binary records (see `Format::binary`), these bypass the output
+            // capturing of tests
+            Err(_) => {
+                let _ = std::io::stdout().write_all(bytes);
+            }
+        }
+    }
+
+    fn flush(&self) {
+        let _ = std::io::stdout().flush();
+    }
 }
 
 pub fn set_output(_fd: u16) {
     // not doing anything
 }
 
+pub fn set_hpet_base(_base: u64) {
+    // not doing anything
+}
+
+/// Colours are on if stdout is a terminal and `NO_COLOR` is not set
+/// (see https://no-color.org), we only check once.
+pub fn supports_color() -> bool {
+    static SUPPORTED: OnceLock<bool> = OnceLock::new();
+    *SUPPORTED
+        .get_or_init(|| color_allowed(std::io::stdout().is_terminal(), env::var_os("NO_COLOR")))
+}
+
+/// `NO_COLOR` only counts if it's not empty.
+pub(crate) fn color_allowed(is_terminal: bool, no_color: Option<OsString>) -> bool {
+    is_terminal
+        && match no_color {
+            Some(value) => value.is_empty(),
+            None => true,
+        }
+}
+
+/// Whether "interrupts" are enabled before `disable_interrupts`.
+pub type InterruptState = bool;
+
+thread_local! {
+    /// There are no interrupts in user space, we keep track of the state
+    /// per thread so the locking can be tested.
+    static INTERRUPTS: Cell<bool> = const { Cell::new(true) };
+}
+
+pub fn disable_interrupts() -> InterruptState {
+    INTERRUPTS.with(|enabled| enabled.replace(false))
+}
+
+pub fn restore_interrupts(enabled: InterruptState) {
+    INTERRUPTS.with(|state| state.set(enabled));
+}
+
+#[cfg(test)]
+pub fn interrupts_enabled() -> bool {
+    INTERRUPTS.with(|enabled| enabled.get())
+}
+
+/// There is nothing to halt, stop the process.
+#[cfg(feature = "panic")]
+pub fn halt() -> ! {
+    std::process::abort()
+}
+
+/// Threads are numbered in the order they first ask for their id.
+///
+/// Threads that log while their thread-locals are destroyed get
+/// `UNKNOWN_CPU`.
+pub fn cpu_id() -> u32 {
+    static NEXT_ID: AtomicU32 = AtomicU32::new(0);
+    thread_local!(static ID: u32 = NEXT_ID.fetch_add(1, Ordering::Relaxed));
+    ID.try_with(|id| *id).unwrap_or(UNKNOWN_CPU)
+}
+
+/// Nanoseconds since the first call (`Instant` uses `CLOCK_MONOTONIC`).
 pub fn get_timestamp() -> u64 {
-    0
+    EPOCH.get_or_init(Instant::now).elapsed().as_nanos() as u64
 }
 
 pub fn has_tsc() -> bool {
-    false
+    true
 }
 
 pub fn has_invariant_tsc() -> bool {
-    false
+    true
 }
 
+/// Our "TSC" counts nanoseconds.
 pub fn get_tsc_frequency_hz() -> Option<u64> {
-    None
+    Some(NS_PER_SEC)
 }
 
 pub fn get_vmm_tsc_frequency_hz() -> Option<u64> {
     None
 }
+
+pub fn calibrate_tsc_frequency_hz() -> Option<u64> {
+    None
+}
@@ -1,32 +1,80 @@
+#[cfg(target_os = "none")]
+use core::arch::asm;
+#[cfg(target_os = "none")]
+use core::ptr;
 use core::sync::atomic::AtomicU16;
+use core::sync::atomic::AtomicU32;
+use core::sync::atomic::AtomicU64;
 use core::sync::atomic::Ordering;
 
 extern crate x86;
 
 use self::x86::io;
 
+#[cfg(not(target_os = "none"))]
+use lock::UNKNOWN_CPU;
+use sink::Sink;
+
 /// One Mhz is that many Hz.
 const MHZ_TO_HZ: u64 = 1000 * 1000;
 
 /// One Khz is that many Hz.
 const KHZ_TO_HZ: u64 = 1000;
 
-/// One sec has that many ns.
-const _NS_PER_SEC: u64 = 1_000_000_000u64;
+/// One sec has that many fs (HPET periods are in femtoseconds).
+#[cfg(target_os = "none")]
+const FS_PER_SEC: u64 = 1_000_000_000_000_000u64;
+
+/// The PIT input clock in Hz.
+#[cfg(target_os = "none")]
+const PIT_TICK_RATE: u64 = 1_193_182;
+
+/// How long we measure the TSC against the PIT/HPET (in ms).
+#[cfg(target_os = "none")]
+const CALIBRATION_MS: u64 = 10;
+
+/// How often we repeat the PIT measurement (we use the fastest run, slow
+/// ones are likely disturbed by SMIs or a preempted vCPU).
+#[cfg(target_os = "none")]
+const PIT_CALIBRATION_RUNS: usize = 3;
+
+/// Give up on the PIT/HPET after that many TSC cycles, it's probably not
+/// there (or not counting). That's 50 ms at 10 GHz, a bit longer on slower
+/// TSCs.
+#[cfg(target_os = "none")]
+const CALIBRATION_TIMEOUT_CYCLES: u64 = 500_000_000;
+
+/// HPET register offsets.
+#[cfg(target_os = "none")]
+const HPET_CAPABILITIES: usize = 0x0;
+#[cfg(target_os = "none")]
+const HPET_CONFIG: usize = 0x10;
+#[cfg(target_os = "none")]
+const HPET_MAIN_COUNTER: usize = 0xf0;
+
+/// HPET_CAPABILITIES: The main counter is 64 bits wide (32 bits otherwise).
+#[cfg(target_os = "none")]
+const HPET_COUNT_SIZE_CAP: u64 = 1 << 13;
+
+/// HPET_CONFIG: Overall enable.
+#[cfg(target_os = "none")]
+const HPET_ENABLE_CNF: u64 = 1;
 
 pub static SERIAL_PRINT_PORT: AtomicU16 = AtomicU16::new(0x3f8); /* default COM1 */
 
-/// Write a string to the output channel.
-pub unsafe fn puts(s: &str) {
-    let port = SERIAL_PRINT_PORT.load(Ordering::Relaxed);
-    for b in s.bytes() {
-        putb(port, b);
-    }
-}
+/// Virtual address of the HPET registers, 0 if we don't have it.
+pub static HPET_BASE: AtomicU64 = AtomicU64::new(0);
 
-pub unsafe fn putc(c: char) {
-    let port = SERIAL_PRINT_PORT.load(Ordering::Relaxed);
-    putb(port, c as u8);
+/// The serial port selected with `set_output` (COM1 by default).
+pub struct Console;
+
+impl Sink for Console {
+    fn write(&self, bytes: &[u8]) {
+        let port = SERIAL_PRINT_PORT.load(Ordering::Relaxed);
+        for b in bytes {
+            unsafe { putb(port, *b) };
+        }
+    }
 }
 
 /// Write a single byte to the output channel.
@@ -40,6 +88,122 @@ pub fn set_output(port: u16) {
     SERIAL_PRINT_PORT.store(port, Ordering::Relaxed);
 }
 
+pub fn set_hpet_base(base: u64) {
+    HPET_BASE.store(base, Ordering::Relaxed);
+}
+
+/// We can't tell what is on the other end of the serial line, assume a
+/// terminal.
+pub fn supports_color() -> bool {
+    true
+}
+
+/// The CPUID leaf `cpu_id` reads the APIC id from (0 until it first
+/// looks).
+#[cfg(target_os = "none")]
+static APIC_ID_LEAF: AtomicU32 = AtomicU32::new(0);
+
+/// CPUID leaf 0xb, extended topology (x2APIC id in EDX).
+#[cfg(target_os = "none")]
+const CPUID_TOPOLOGY: u32 = 0xb;
+
+/// CPUID leaf 1, feature information (initial APIC id in EBX[31:24]).
+#[cfg(target_os = "none")]
+const CPUID_FEATURES: u32 = 1;
+
+/// APIC id of the current core: The x2APIC id from the extended topology
+/// leaf if CPUID has it, the initial (8-bit) APIC id otherwise.
+///
+/// This runs on every line we print, so we only check once which leaf to
+/// use and execute a single CPUID afterwards. CPUID still traps in a VM,
+/// kernels that keep the id in a per-core area should use `set_cpu_id`.
+#[cfg(target_os = "none")]
+pub fn cpu_id() -> u32 {
+    let mut leaf = APIC_ID_LEAF.load(Ordering::Relaxed);
+    if leaf == 0 {
+        let has_topology = x86::cpuid::CpuId::new()
+            .get_extended_topology_info()
+            .and_then(|mut levels| levels.next())
+            .is_some();
+        leaf = if has_topology {
+            CPUID_TOPOLOGY
+        } else {
+            CPUID_FEATURES
+        };
+        APIC_ID_LEAF.store(leaf, Ordering::Relaxed);
+    }
+    let regs = x86::cpuid::native_cpuid::cpuid_count(leaf, 0);
+    match leaf {
+        CPUID_TOPOLOGY => regs.edx,
+        _ => regs.ebx >> 24,
+    }
+}
+
+/// In user space (e.g., kvmtests with `use_ioports`) threads move between
+/// cores, so the APIC id doesn't tell them apart. Threads are numbered like
+/// on unix instead.
+#[cfg(not(target_os = "none"))]
+pub fn cpu_id() -> u32 {
+    static NEXT_ID: AtomicU32 = AtomicU32::new(0);
+    thread_local!(static ID: u32 = NEXT_ID.fetch_add(1, Ordering::Relaxed));
+    ID.try_with(|id| *id).unwrap_or(UNKNOWN_CPU)
+}
+
+/// RFLAGS before `disable_interrupts`.
+pub type InterruptState = u64;
+
+/// RFLAGS.IF
+#[cfg(target_os = "none")]
+const RFLAGS_IF: u64 = 1 << 9;
+
+/// Disable interrupts, returns the previous RFLAGS.
+#[cfg(target_os = "none")]
+pub fn disable_interrupts() -> InterruptState {
+    let rflags: u64;
+    // No `nomem`, the compiler must not move memory accesses out of the
+    // critical section
+    unsafe {
+        asm!("pushfq", "pop {}", "cli", out(reg) rflags);
+    }
+    rflags
+}
+
+/// Enable interrupts again if they were on before `disable_interrupts`.
+#[cfg(target_os = "none")]
+pub fn restore_interrupts(rflags: InterruptState) {
+    if rflags & RFLAGS_IF != 0 {
+        unsafe {
+            asm!("sti", options(nostack));
+        }
+    }
+}
+
+/// With ioports on a hosted OS we run in user space and can't (and don't
+/// need to) disable interrupts.
+#[cfg(not(target_os = "none"))]
+pub fn disable_interrupts() -> InterruptState {
+    0
+}
+
+#[cfg(not(target_os = "none"))]
+pub fn restore_interrupts(_rflags: InterruptState) {}
+
+/// Stop this core for good.
+#[cfg(all(feature = "panic", target_os = "none"))]
+pub fn halt() -> ! {
+    loop {
+        unsafe {
+            asm!("cli", "hlt", options(nomem, nostack));
+        }
+    }
+}
+
+/// We're a process (using ioports), stop that.
+#[cfg(all(feature = "panic", not(target_os = "none")))]
+pub fn halt() -> ! {
+    ::std::process::abort()
+}
+
 pub fn get_timestamp() -> u64 {
     unsafe { x86::time::rdtsc() }
 }
@@ -86,3 +250,123 @@ pub fn get_vmm_tsc_frequency_hz() -> Option<u64> {
         .get_hypervisor_info()
         .and_then(|hv| hv.tsc_frequency().map(|tsc_khz| tsc_khz as u64 * KHZ_TO_HZ))
 }
+
+/// Measure the TSC frequency against the HPET (if we know where it is) or
+/// the PIT.
+///
+/// Interrupts are disabled meanwhile, an interrupt handler running during
+/// the measurement would make it (a lot) less precise.
+#[cfg(target_os = "none")]
+pub fn calibrate_tsc_frequency_hz() -> Option<u64> {
+    let interrupts = disable_interrupts();
+    let hpet_base = HPET_BASE.load(Ordering::Relaxed);
+    let from_hpet = if hpet_base != 0 {
+        unsafe { calibrate_tsc_hpet(hpet_base as usize) }
+    } else {
+        None
+    };
+
+    let hz = from_hpet.or_else(|| unsafe { calibrate_tsc_pit() });
+    restore_interrupts(interrupts);
+    hz
+}
+
+/// In user space (e.g., kvmtests with `use_ioports`) we don't own the PIT
+/// and the HPET, we can't measure.
+#[cfg(not(target_os = "none"))]
+pub fn calibrate_tsc_frequency_hz() -> Option<u64> {
+    None
+}
+
+/// Count TSC cycles for `CALIBRATION_MS` using PIT channel 2 in one-shot mode.
+///
+/// Works like the `pit_calibrate_tsc` fallback in Linux: channel 2 is gated
+/// through port 0x61 (speaker off) and we poll its OUT pin which goes high
+/// once the count reaches zero.
+#[cfg(target_os = "none")]
+unsafe fn calibrate_tsc_pit() -> Option<u64> {
+    let latch = PIT_TICK_RATE / (1000 / CALIBRATION_MS);
+    let mut min_cycles = u64::MAX;
+
+    for _ in 0..PIT_CALIBRATION_RUNS {
+        // Set the gate high, disable speaker
+        io::outb(0x61, (io::inb(0x61) & !0x02) | 0x01);
+        // Channel 2, mode 0 (interrupt on terminal count), binary, lobyte/hibyte
+        io::outb(0x43, 0xb0);
+        io::outb(0x42, (latch & 0xff) as u8);
+        io::outb(0x42, (latch >> 8) as u8);
+
+        let start = x86::time::rdtsc();
+        let mut polls = 0;
+        while (io::inb(0x61) & 0x20) == 0 {
+            polls += 1;
+            if x86::time::rdtsc() - start > CALIBRATION_TIMEOUT_CYCLES {
+                return None;
+            }
+        }
+        let end = x86::time::rdtsc();
+
+        // If OUT was high immediately the PIT doesn't work (or doesn't exist
+        // and reads return 0xff)
+        if polls < 1000 {
+            return None;
+        }
+        min_cycles = core::cmp::min(min_cycles, end - start);
+    }
+
+    Some(min_cycles * (1000 / CALIBRATION_MS))
+}
+
+/// Count TSC cycles for `CALIBRATION_MS` using the HPET main counter.
+#[cfg(target_os = "none")]
+unsafe fn calibrate_tsc_hpet(base: usize) -> Option<u64> {
+    let read = |offset: usize| ptr::read_volatile((base + offset) as *const u64);
+    let write = |offset: usize, val: u64| ptr::write_volatile((base + offset) as *mut u64, val);
+
+    // Upper 32 bits: Main counter tick period in femtoseconds
+    let capabilities = read(HPET_CAPABILITIES);
+    let period_fs = capabilities >> 32;
+    if period_fs == 0 || period_fs > 100_000_000 {
+        // Spec says period must be <= 100 ns, probably not an HPET
+        return None;
+    }
+
+    let config = read(HPET_CONFIG);
+    if config & HPET_ENABLE_CNF == 0 {
+        write(HPET_CONFIG, config | HPET_ENABLE_CNF);
+    }
+
+    // A 32-bit counter wraps every ~5 minutes (at the common 14.3 MHz), so
+    // it might during the measurement
+    let mask = if capabilities & HPET_COUNT_SIZE_CAP != 0 {
+        u64::MAX
+    } else {
+        u32::MAX as u64
+    };
+    let elapsed = |start: u64, end: u64| end.wrapping_sub(start) & mask;
+
+    let ticks = (CALIBRATION_MS * FS_PER_SEC / 1000) / period_fs;
+    let hpet_start = read(HPET_MAIN_COUNTER) & mask;
+    let tsc_start = x86::time::rdtsc();
+    let mut hpet_end = hpet_start;
+    while elapsed(hpet_start, hpet_end) < ticks {
+        hpet_end = read(HPET_MAIN_COUNTER) & mask;
+        if x86::time::rdtsc() - tsc_start > CALIBRATION_TIMEOUT_CYCLES {
+            break;
+        }
+    }
+    let tsc_end = x86::time::rdtsc();
+
+    if config & HPET_ENABLE_CNF == 0 {
+        // Leave it the way we found it
+        write(HPET_CONFIG, config);
+    }
+
+    if elapsed(hpet_start, hpet_end) < ticks {
+        // The counter doesn't count
+        return None;
+    }
+    let elapsed_fs = elapsed(hpet_start, hpet_end) as u128 * period_fs as u128;
+    let hz = (tsc_end - tsc_start) as u128 * FS_PER_SEC as u128 / elapsed_fs;
+    Some(hz as u64)
+}
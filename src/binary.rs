@@ -0,0 +1,593 @@
+//! Compact binary log records with deferred formatting (enabled by the
+//! `binary` feature).
+//!
+//! With `Format::binary` klogger doesn't format log records on the target,
+//! instead it writes them as COBS-framed binary records that
+//! `klogger-decode` turns back into text on the host. Records logged with
+//! `binlog!` only contain the timestamp, level, a reference to the format
+//! string and the raw arguments, the format string itself is stored in the
+//! `klogger_fmt` section of the ELF file. Records of the `log` crate are
+//! formatted as usual and sent as strings.
+//!
+//! Wire format (before COBS encoding, every frame ends with a 0 byte):
+//!
+//! ```text
+//! record  := header [timestamp] [id] arg*
+//! header  := u8: level (bits 0-2), timestamp kind (bits 3-4: none, ns,
+//!            cycles), set bit 5 if the record has no interned format
+//!            string (args are then the target and the message)
+//! id      := zigzag varint: offset of the interned strings from the anchor
+//!            of the `klogger_fmt` section
+//! arg     := tag payload
+//!            0: unsigned varint, 1: signed zigzag varint, 2: bool (u8),
+//!            3: char (varint), 4: str (varint length, bytes), 5: f64 (LE)
+//!            bits 4-5 of a signed tag: log2 of the size of the integer in
+//!            bytes (so `{:x}` of -1i8 is `ff`)
+//! ```
+//!
+//! An interned entry consists of the module path and the format string,
+//! both 0-terminated. Since the section has to be kept in the final binary,
+//! linker scripts need a `KEEP(*(klogger_fmt))`.
+
+use core::fmt;
+use core::fmt::Write;
+
+pub use log::Level;
+use log::{Log, Metadata, Record};
+
+use super::{lock, sink, ElapsedTime, Sink, TimestampMode, LOGGER};
+#[cfg(feature = "kv")]
+use kv;
+
+/// Marks the start of the interned strings, `klogger-decode` searches for
+/// it in the `klogger_fmt` section.
+#[link_section = "klogger_fmt"]
+#[used]
+static ANCHOR: [u8; 15] = *b"KLOGGER_FMT_V1\0";
+
+const TAG_UNSIGNED: u8 = 0;
+const TAG_SIGNED: u8 = 1;
+const TAG_BOOL: u8 = 2;
+const TAG_CHAR: u8 = 3;
+const TAG_STR: u8 = 4;
+const TAG_F64: u8 = 5;
+
+/// Header bit for records without interned format string.
+const DYNAMIC: u8 = 1 << 5;
+
+/// Writes a frame to a sink, COBS encoding it on the fly.
+pub struct Frame<'a> {
+    sink: &'a dyn Sink,
+    /// Current COBS block, `block[0]` is reserved for the block length.
+    block: [u8; 255],
+    len: usize,
+}
+
+impl<'a> Frame<'a> {
+    fn new(sink: &'a dyn Sink) -> Frame<'a> {
+        Frame {
+            sink,
+            block: [0; 255],
+            len: 1,
+        }
+    }
+
+    fn flush_block(&mut self) {
+        self.block[0] = self.len as u8;
+        self.sink.write(&self.block[..self.len]);
+        self.len = 1;
+    }
+
+    fn byte(&mut self, b: u8) {
+        if b == 0 {
+            self.flush_block();
+        } else {
+            self.block[self.len] = b;
+            self.len += 1;
+            // A full block doesn't imply a 0 byte
+            if self.len == self.block.len() {
+                self.flush_block();
+            }
+        }
+    }
+
+    fn bytes(&mut self, bytes: &[u8]) {
+        for &b in bytes {
+            self.byte(b);
+        }
+    }
+
+    fn varint(&mut self, mut value: u64) {
+        while value >= 0x80 {
+            self.byte(value as u8 | 0x80);
+            value >>= 7;
+        }
+        self.byte(value as u8);
+    }
+
+    fn zigzag(&mut self, value: i64) {
+        self.varint(((value << 1) ^ (value >> 63)) as u64);
+    }
+
+    fn header(&mut self, level: Level, elapsed: &ElapsedTime, flags: u8) {
+        let (kind, ts) = match *elapsed {
+            ElapsedTime::Undetermined => (0, 0),
+            ElapsedTime::Nanoseconds(ns) => (1, ns),
+            ElapsedTime::Cycles(cycles) => (2, cycles),
+        };
+        self.byte(level as u8 | (kind << 3) | flags);
+        if kind != 0 {
+            self.varint(ts);
+        }
+    }
+
+    fn str(&mut self, s: &str) {
+        self.byte(TAG_STR);
+        self.varint(s.len() as u64);
+        self.bytes(s.as_bytes());
+    }
+
+    /// Finish the frame with the 0 delimiter.
+    fn finish(mut self) {
+        self.flush_block();
+        self.sink.write(&[0]);
+    }
+}
+
+/// Writes exactly `left` bytes of a string argument into a frame.
+///
+/// Output beyond `left` is cut (at a character boundary), whatever is
+/// missing at the end is filled up with spaces. That only happens if
+/// formatting the same message twice doesn't give the same length.
+struct FrameStr<'f, 'a> {
+    frame: &'f mut Frame<'a>,
+    left: usize,
+}
+
+impl<'f, 'a> fmt::Write for FrameStr<'f, 'a> {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        let mut n = core::cmp::min(s.len(), self.left);
+        while !s.is_char_boundary(n) {
+            n -= 1;
+        }
+        self.frame.bytes(&s.as_bytes()[..n]);
+        self.left -= n;
+        Ok(())
+    }
+}
+
+impl<'f, 'a> FrameStr<'f, 'a> {
+    fn finish(self) {
+        for _ in 0..self.left {
+            self.frame.byte(b' ');
+        }
+    }
+}
+
+/// Counts the bytes written to it.
+struct Length(usize);
+
+impl fmt::Write for Length {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        self.0 += s.len();
+        Ok(())
+    }
+}
+
+/// Arguments of `binlog!` records.
+pub trait Encode {
+    fn encode(&self, frame: &mut Frame);
+}
+
+impl<T: Encode + ?Sized> Encode for &T {
+    fn encode(&self, frame: &mut Frame) {
+        (**self).encode(frame)
+    }
+}
+
+macro_rules! encode_int {
+    ($tag:ident, $method:ident, $as:ty, $($ty:ty)*) => {$(
+        impl Encode for $ty {
+            fn encode(&self, frame: &mut Frame) {
+                frame.byte($tag);
+                frame.$method(*self as $as);
+            }
+        }
+    )*};
+}
+
+macro_rules! encode_signed {
+    ($($ty:ty)*) => {$(
+        impl Encode for $ty {
+            fn encode(&self, frame: &mut Frame) {
+                let size = core::mem::size_of::<$ty>().trailing_zeros() as u8;
+                frame.byte(TAG_SIGNED | (size << 4));
+                frame.zigzag(*self as i64);
+            }
+        }
+    )*};
+}
+
+encode_int!(TAG_UNSIGNED, varint, u64, u8 u16 u32 u64 usize);
+encode_signed!(i8 i16 i32 i64 isize);
+
+impl Encode for bool {
+    fn encode(&self, frame: &mut Frame) {
+        frame.byte(TAG_BOOL);
+        frame.byte(*self as u8);
+    }
+}
+
+impl Encode for char {
+    fn encode(&self, frame: &mut Frame) {
+        frame.byte(TAG_CHAR);
+        frame.varint(*self as u64);
+    }
+}
+
+impl Encode for str {
+    fn encode(&self, frame: &mut Frame) {
+        frame.str(self);
+    }
+}
+
+impl Encode for f64 {
+    fn encode(&self, frame: &mut Frame) {
+        frame.byte(TAG_F64);
+        frame.bytes(&self.to_le_bytes());
+    }
+}
+
+impl Encode for f32 {
+    fn encode(&self, frame: &mut Frame) {
+        (*self as f64).encode(frame)
+    }
+}
+
+/// Length of the interned entry for `module` and `format`.
+#[doc(hidden)]
+pub const fn entry_len(module: &str, format: &str) -> usize {
+    module.len() + format.len() + 2
+}
+
+/// The interned entry for `module` and `format` (`N` is `entry_len`).
+#[doc(hidden)]
+pub const fn entry<const N: usize>(module: &str, format: &str) -> [u8; N] {
+    let mut entry = [0; N];
+    let mut i = 0;
+    while i < module.len() {
+        entry[i] = module.as_bytes()[i];
+        i += 1;
+    }
+    let mut j = 0;
+    while j < format.len() {
+        entry[i + 1 + j] = format.as_bytes()[j];
+        j += 1;
+    }
+    entry
+}
+
+/// Does `format` only use placeholders `klogger-decode` can render (see
+/// `binlog!`)?
+#[doc(hidden)]
+pub const fn supported_format(format: &str) -> bool {
+    let f = format.as_bytes();
+    let mut i = 0;
+    while i < f.len() {
+        if f[i] == b'{' {
+            if i + 1 < f.len() && f[i + 1] == b'{' {
+                i += 2;
+                continue;
+            }
+            i += 1;
+            // No positional or named arguments, so the spec starts right away
+            if i < f.len() && f[i] == b':' {
+                i += 1;
+                if i < f.len() && matches!(f[i], b'<' | b'>' | b'^') {
+                    i += 1;
+                }
+                if i < f.len() && f[i] == b'#' {
+                    i += 1;
+                }
+                while i < f.len() && f[i].is_ascii_digit() {
+                    i += 1;
+                }
+                if i < f.len() && matches!(f[i], b'?' | b'x' | b'X' | b'b' | b'o') {
+                    i += 1;
+                }
+            }
+            if i == f.len() || f[i] != b'}' {
+                return false;
+            }
+        }
+        i += 1;
+    }
+    true
+}
+
+/// Does klogger write binary records?
+#[doc(hidden)]
+pub fn active() -> bool {
+    // Without the config, `write_text` falls back to the default format
+    LOGGER
+        .read_config()
+        .is_some_and(|config| config.format.as_ref().is_some_and(|f| f.is_binary()))
+}
+
+/// Would klogger log a record with `level` and `target`?
+#[doc(hidden)]
+pub fn enabled(level: Level, target: &str) -> bool {
+    level <= log::max_level()
+        && LOGGER.enabled(&Metadata::builder().level(level).target(target).build())
+}
+
+/// Write a `binlog!` record with the interned `entry`.
+#[doc(hidden)]
+pub fn write(level: Level, target: &str, entry: &'static [u8], args: &[&dyn Encode]) {
+    let timestamps = LOGGER
+        .read_config()
+        .map_or(TimestampMode::Auto, |config| config.timestamps);
+    let elapsed = LOGGER.elapsed(timestamps);
+    let id = entry.as_ptr() as i64 - ANCHOR.as_ptr() as i64;
+    let metadata = Metadata::builder().level(level).target(target).build();
+
+    let _line_lock = match lock::line() {
+        Some(lock) => lock,
+        None => return,
+    };
+    sink::for_each_enabled(&metadata, None, |sink, _| {
+        let mut frame = Frame::new(sink);
+        frame.header(level, &elapsed, 0);
+        frame.zigzag(id);
+        for arg in args {
+            arg.encode(&mut frame);
+        }
+        frame.finish();
+    });
+}
+
+/// Log a `binlog!` record as text (if klogger doesn't write binary).
+#[doc(hidden)]
+pub fn write_text(level: Level, target: &str, file: &str, line: u32, args: core::fmt::Arguments) {
+    log::logger().log(
+        &Record::builder()
+            .args(args)
+            .level(level)
+            .target(target)
+            .module_path(Some(target))
+            .file(Some(file))
+            .line(Some(line))
+            .build(),
+    );
+}
+
+/// Write the message of `record` along with its key-value pairs (like text
+/// output does).
+fn write_message<W: Write>(w: &mut W, record: &Record) -> fmt::Result {
+    write!(w, "{}", record.args())?;
+    #[cfg(feature = "kv")]
+    kv::write_logfmt(w, record.key_values())?;
+    Ok(())
+}
+
+/// Write a record of the `log` crate (formats the message).
+///
+/// The message is formatted twice, first to find out its length, then
+/// straight into the frame.
+pub(crate) fn write_record(sink: &dyn Sink, record: &Record, elapsed: &ElapsedTime) {
+    let mut len = Length(0);
+    let _ = write_message(&mut len, record);
+
+    let mut frame = Frame::new(sink);
+    frame.header(record.level(), elapsed, DYNAMIC);
+    frame.str(record.target());
+    frame.byte(TAG_STR);
+    frame.varint(len.0 as u64);
+    let mut message = FrameStr {
+        frame: &mut frame,
+        left: len.0,
+    };
+    let _ = write_message(&mut message, record);
+    message.finish();
+    frame.finish();
+}
+
+/// Log with deferred formatting, e.g., `binlog!(Level::Info, "irq {} on
+/// core {}", irq, core)`.
+///
+/// If klogger writes binary records (see `Format::binary`), the format
+/// string is interned and only the arguments are sent. Otherwise this is the
+/// same as `log!`. Arguments can be integers, floats, `bool`, `char` and
+/// strings, the format string supports `{}`, `{:?}` and the integer formats
+/// `x`, `X`, `b` and `o` (with alignment, width, `0` and `#`). Other
+/// placeholders (e.g., `{name}`, `{:.2}` or `{:*^5}`) don't compile.
+///
+/// Binary records aren't formatted on the target, so message patterns
+/// (`/regex` in the filter of klogger or of a sink) don't apply to them.
+#[macro_export]
+macro_rules! binlog {
+    ($level:expr, $fmt:literal $(, $arg:expr)* $(,)?) => {{
+        const _: () = assert!(
+            $crate::binary::supported_format($fmt),
+            "unsupported placeholder for binlog! (see its documentation)"
+        );
+        let level: $crate::binary::Level = $level;
+        if $crate::binary::enabled(level, module_path!()) {
+            if $crate::binary::active() {
+                const LEN: usize = $crate::binary::entry_len(module_path!(), $fmt);
+                #[link_section = "klogger_fmt"]
+                #[used]
+                static ENTRY: [u8; LEN] = $crate::binary::entry::<LEN>(module_path!(), $fmt);
+                $crate::binary::write(
+                    level,
+                    module_path!(),
+                    &ENTRY,
+                    &[$(&$arg as &dyn $crate::binary::Encode),*],
+                );
+            } else {
+                $crate::binary::write_text(
+                    level,
+                    module_path!(),
+                    file!(),
+                    line!(),
+                    format_args!($fmt $(, $arg)*),
+                );
+            }
+        }
+    }};
+}
+
+#[cfg(test)]
+mod test {
+    use core::cell::Cell;
+    use core::fmt;
+
+    use log::{Level, Record};
+
+    use super::{entry, entry_len, supported_format, write_record, Encode, Frame, DYNAMIC};
+    use sink::test::Memory;
+    use ElapsedTime;
+
+    #[test]
+    fn frame_layout() {
+        let out: Memory<512> = Memory::new();
+        let mut frame = Frame::new(&out);
+        frame.header(Level::Warn, &ElapsedTime::Nanoseconds(300), 0);
+        frame.zigzag(-2);
+        for arg in [&0u8 as &dyn Encode, &-1i32, &"hi", &true] {
+            arg.encode(&mut frame);
+        }
+        frame.finish();
+
+        // Unencoded: [0x0a, 0xac, 0x02, 0x03, 0, 0, 0x21, 1, 4, 2, b'h', b'i', 2, 1]
+        assert_eq!(
+            &out.0.lock()[..],
+            &[5, 0x0a, 0xac, 0x02, 0x03, 1, 9, 0x21, 1, 4, 2, b'h', b'i', 2, 1, 0]
+        );
+    }
+
+    #[test]
+    fn long_runs_are_split() {
+        let out: Memory<512> = Memory::new();
+        let mut frame = Frame::new(&out);
+        frame.header(Level::Info, &ElapsedTime::Undetermined, DYNAMIC);
+        frame.str(core::str::from_utf8(&[b'x'; 300]).unwrap());
+        frame.finish();
+
+        let out = out.0.lock();
+        // Header, tag and length (2 bytes) + 250 bytes, then the rest
+        assert_eq!(out[0], 255);
+        assert_eq!(&out[1..5], &[0x23, 4, 0xac, 0x02]);
+        assert_eq!(out[255], 51);
+        assert_eq!(out.len(), 255 + 51 + 1);
+        assert!(!out[..out.len() - 1].contains(&0));
+    }
+
+    #[test]
+    fn whole_message() {
+        let out: Memory<512> = Memory::new();
+        let long = core::str::from_utf8(&[b'x'; 300]).unwrap();
+        write_record(
+            &out,
+            &Record::builder()
+                .args(format_args!("{}", long))
+                .target("net")
+                .build(),
+            &ElapsedTime::Undetermined,
+        );
+
+        let out = out.0.lock();
+        // Header, "net", tag and length (2 bytes) + 245 bytes, then the rest
+        assert_eq!(
+            &out[..10],
+            &[255, 0x23, 4, 3, b'n', b'e', b't', 4, 0xac, 0x02]
+        );
+        assert_eq!(out.iter().filter(|&&b| b == b'x').count(), 300);
+        assert_eq!(out.len(), 255 + 56 + 1);
+    }
+
+    /// Gets shorter every time it's formatted.
+    struct Shrinking(Cell<usize>);
+
+    impl fmt::Display for Shrinking {
+        fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+            let len = self.0.get();
+            self.0.set(len - 1);
+            f.write_str(&"abcd"[..len])
+        }
+    }
+
+    #[test]
+    fn message_length_changes() {
+        let out: Memory<64> = Memory::new();
+        let shrinking = Shrinking(Cell::new(4));
+        write_record(
+            &out,
+            &Record::builder()
+                .args(format_args!("{}", shrinking))
+                .build(),
+            &ElapsedTime::Undetermined,
+        );
+        assert!(out.0.lock().ends_with(b"\x04abc \0"));
+    }
+
+    #[test]
+    #[cfg(feature = "kv")]
+    fn key_values() {
+        let out: Memory<64> = Memory::new();
+        let kvs = [("irq", 5)];
+        write_record(
+            &out,
+            &Record::builder()
+                .args(format_args!("enabled"))
+                .key_values(&kvs)
+                .build(),
+            &ElapsedTime::Undetermined,
+        );
+        assert!(out.0.lock().ends_with(b"\x0denabled irq=5\0"));
+    }
+
+    #[test]
+    fn interned_entry() {
+        const LEN: usize = entry_len("net", "irq {}");
+        const ENTRY: [u8; LEN] = entry::<LEN>("net", "irq {}");
+        assert_eq!(&ENTRY, b"net\0irq {}\0");
+    }
+
+    #[test]
+    fn format_specs() {
+        for ok in [
+            "",
+            "{} {:?}",
+            "{{}} {:#x}",
+            "{:>8}",
+            "{:<#010b}",
+            "{:^3o}",
+            "}}",
+        ] {
+            assert!(supported_format(ok), "{}", ok);
+        }
+        for unsupported in [
+            "{0}", "{name}", "{:.2}", "{:*^5}", "{:+}", "{:e}", "{:x?}", "{",
+        ] {
+            assert!(!supported_format(unsupported), "{}", unsupported);
+        }
+    }
+
+    #[test]
+    fn macro_expands() {
+        // Trace is never enabled in tests, this only checks that `binlog!`
+        // compiles for the supported argument types
+        let name = "eth0";
+        binlog!(
+            Level::Trace,
+            "{} {:#x} {} {} {} {}",
+            name,
+            1u64,
+            -2i8,
+            'c',
+            0.5f32,
+            false
+        );
+    }
+}
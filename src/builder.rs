@@ -0,0 +1,348 @@
+//! Configure and install klogger.
+//!
+//! ```no_run
+//! klogger::KLoggerBuilder::new()
+//!     .filter("info,crate1::mod1=trace")
+//!     .output(0x2f8)
+//!     .color(klogger::ColorMode::Never)
+//!     .install()
+//!     .expect("klogger already installed");
+//! ```
+
+use core::convert::TryInto;
+use core::str::FromStr;
+
+use heapless::Vec;
+
+use super::{
+    arch, format, lock, parse_args_with, set_cpu_id, sink, Clock, Directives, Error, Format,
+    PathTrim, Sink, Theme, LOGGER,
+};
+use sink::{Output, MAX_SINKS};
+
+/// Whether log lines contain ANSI colour codes.
+///
+/// Without the `colors` feature klogger never emits colour codes.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ColorMode {
+    /// Always emit colour codes.
+    Always,
+    /// Never emit colour codes.
+    Never,
+    /// Emit colour codes if the output supports them: Always on bare metal,
+    /// on unix if stdout is a terminal and `NO_COLOR` is not set.
+    Auto,
+}
+
+impl ColorMode {
+    /// Do we print colour codes in this mode?
+    pub(crate) fn enabled(self) -> bool {
+        cfg!(feature = "colors")
+            && match self {
+                ColorMode::Always => true,
+                ColorMode::Never => false,
+                ColorMode::Auto => arch::supports_color(),
+            }
+    }
+}
+
+/// Parses "always", "never" or "auto" (ignoring case), as used in filter
+/// specs (e.g., "info,color=never").
+impl FromStr for ColorMode {
+    type Err = ();
+
+    fn from_str(s: &str) -> Result<ColorMode, ()> {
+        if s.eq_ignore_ascii_case("always") {
+            Ok(ColorMode::Always)
+        } else if s.eq_ignore_ascii_case("never") {
+            Ok(ColorMode::Never)
+        } else if s.eq_ignore_ascii_case("auto") {
+            Ok(ColorMode::Auto)
+        } else {
+            Err(())
+        }
+    }
+}
+
+/// What we print as the timestamp of a log line.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TimestampMode {
+    /// Nanoseconds since init if we know the counter frequency, raw counter
+    /// cycles otherwise.
+    Auto,
+    /// Raw counter cycles.
+    Cycles,
+    /// No timestamps.
+    Off,
+}
+
+/// Which source location the default format prints after the target.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SourceLocation {
+    /// None (`format::DEFAULT_FORMAT`).
+    Off,
+    /// `file:line` (`format::LOCATION_FORMAT`).
+    File,
+    /// Module path and `file:line` (`format::MODULE_LOCATION_FORMAT`).
+    ModuleAndFile,
+}
+
+/// Builder for the klogger configuration, `install` sets it as the logger
+/// used by the `log` crate.
+pub struct KLoggerBuilder<'a> {
+    filter: &'a str,
+    format: Option<&'a str>,
+    location: SourceLocation,
+    paths: PathTrim,
+    show_cpu: bool,
+    cpu_id: Option<fn() -> u32>,
+    json: bool,
+    #[cfg(feature = "binary")]
+    binary: bool,
+    output: Option<u64>,
+    hpet_base: Option<u64>,
+    color: ColorMode,
+    theme: Theme,
+    timestamps: TimestampMode,
+    sinks: Vec<(&'static dyn Sink, &'a str), MAX_SINKS>,
+    /// Set if `sink` was called too often, reported by `install`.
+    too_many_sinks: bool,
+    /// Ignore filter directives that don't fit (for `init`).
+    lenient: bool,
+}
+
+impl<'a> KLoggerBuilder<'a> {
+    /// A builder with the defaults: Log nothing, write to the default output
+    /// of the architecture, `ColorMode::Auto` and timestamps on.
+    pub fn new() -> KLoggerBuilder<'a> {
+        KLoggerBuilder {
+            filter: "",
+            format: None,
+            location: SourceLocation::Off,
+            paths: PathTrim::Full,
+            show_cpu: false,
+            cpu_id: None,
+            json: false,
+            #[cfg(feature = "binary")]
+            binary: false,
+            output: None,
+            hpet_base: None,
+            color: ColorMode::Auto,
+            theme: Theme::DARK,
+            timestamps: TimestampMode::Auto,
+            sinks: Vec::new(),
+            too_many_sinks: false,
+            lenient: false,
+        }
+    }
+
+    /// Which records to log (e.g., "info,crate1::mod1=trace").
+    pub fn filter(mut self, spec: &'a str) -> KLoggerBuilder<'a> {
+        self.filter = spec;
+        self
+    }
+
+    /// Layout of log lines (see `Format`), defaults to
+    /// `format::DEFAULT_FORMAT`.
+    pub fn format(mut self, template: &'a str) -> KLoggerBuilder<'a> {
+        self.format = Some(template);
+        self
+    }
+
+    /// Add the source location of records to the default format (ignored
+    /// if `format` is set, use the `{module_path}`, `{file}` and `{line}`
+    /// tokens there).
+    pub fn source_location(mut self, location: SourceLocation) -> KLoggerBuilder<'a> {
+        self.location = location;
+        self
+    }
+
+    /// Add the CPU id (`cpuN`) after the level to the default format.
+    pub fn show_cpu(mut self, show: bool) -> KLoggerBuilder<'a> {
+        self.show_cpu = show;
+        self
+    }
+
+    /// How to figure out the core that logs a record (see `set_cpu_id`).
+    pub fn cpu_id(mut self, cpu_id: fn() -> u32) -> KLoggerBuilder<'a> {
+        self.cpu_id = Some(cpu_id);
+        self
+    }
+
+    /// Shorten the file paths printed for `{file}`.
+    pub fn trim_paths(mut self, paths: PathTrim) -> KLoggerBuilder<'a> {
+        self.paths = paths;
+        self
+    }
+
+    /// Write log records as JSON lines instead (see `Format::json`).
+    pub fn json(mut self) -> KLoggerBuilder<'a> {
+        self.json = true;
+        self
+    }
+
+    /// Write log records as binary frames instead (see `Format::binary`).
+    #[cfg(feature = "binary")]
+    pub fn binary(mut self) -> KLoggerBuilder<'a> {
+        self.binary = true;
+        self
+    }
+
+    /// Where `Console` writes to.
+    ///
+    /// This is the serial port on x86, it's ignored on aarch64 and unix
+    /// (which always writes to stdout).
+    pub fn output(mut self, output: u64) -> KLoggerBuilder<'a> {
+        self.output = Some(output);
+        self
+    }
+
+    /// Virtual address of the HPET registers (only used on x86).
+    ///
+    /// If CPUID and the hypervisor don't report the TSC frequency, we measure
+    /// it against the HPET (if set), or the PIT otherwise.
+    pub fn hpet_base(mut self, base: u64) -> KLoggerBuilder<'a> {
+        self.hpet_base = Some(base);
+        self
+    }
+
+    /// Whether log lines are coloured, a `color=` directive in the filter
+    /// takes precedence.
+    pub fn color(mut self, mode: ColorMode) -> KLoggerBuilder<'a> {
+        self.color = mode;
+        self
+    }
+
+    /// Colours of log lines, defaults to `Theme::DARK`.
+    pub fn theme(mut self, theme: Theme) -> KLoggerBuilder<'a> {
+        self.theme = theme;
+        self
+    }
+
+    /// What to print as timestamp.
+    pub fn timestamps(mut self, mode: TimestampMode) -> KLoggerBuilder<'a> {
+        self.timestamps = mode;
+        self
+    }
+
+    /// Also write output to `sink` (see `add_sink`).
+    pub fn sink(self, sink: &'static dyn Sink) -> KLoggerBuilder<'a> {
+        self.filtered_sink(sink, "")
+    }
+
+    /// Also write log records matching `spec` to `sink` (see
+    /// `add_filtered_sink`).
+    pub fn filtered_sink(mut self, sink: &'static dyn Sink, spec: &'a str) -> KLoggerBuilder<'a> {
+        if self.sinks.push((sink, spec)).is_err() {
+            self.too_many_sinks = true;
+        }
+        self
+    }
+
+    /// Ignore directives in `filter` that don't fit (with a warning) instead
+    /// of failing.
+    pub(crate) fn lenient(mut self) -> KLoggerBuilder<'a> {
+        self.lenient = true;
+        self
+    }
+
+    /// Install klogger as the logger of the `log` crate.
+    ///
+    /// Fails if the configuration is invalid or a logger was installed
+    /// already.
+    pub fn install(self) -> Result<(), Error> {
+        if self.too_many_sinks {
+            return Err(Error::TooManySinks);
+        }
+        let mut filter = Directives::new();
+        let spec = parse_args_with(&mut filter, self.filter, !self.lenient)?;
+        let format = match self.format {
+            #[cfg(feature = "binary")]
+            _ if self.binary => Format::binary(),
+            _ if self.json => Format::json(),
+            Some(template) => Format::parse(template)?,
+            None => Format::parse(&format::default_template(self.show_cpu, self.location))?,
+        };
+        let output = self
+            .output
+            .map(|output| output.try_into().map_err(|_| Error::InvalidOutput(output)))
+            .transpose()?;
+        let mut outputs = Vec::new();
+        for &(sink, spec) in self.sinks.iter() {
+            // Can't fail, `self.sinks` has the same capacity
+            let _ = outputs.push(Output::new(sink, spec)?);
+        }
+
+        // Nothing logs until we set the max level. If we're not the logger
+        // (or the sinks don't fit), the one that is keeps its configuration.
+        sink::add_outputs_with(outputs, || Ok(log::set_logger(&LOGGER)?))?;
+
+        if let Some(output) = output {
+            arch::set_output(output);
+        }
+        if let Some(base) = self.hpet_base {
+            arch::set_hpet_base(base);
+        }
+        if let Some(cpu_id) = self.cpu_id {
+            set_cpu_id(cpu_id);
+        }
+        LOGGER.clock.call_once(Clock::detect);
+        let max_level = lock::without_interrupts(|| {
+            let mut config = LOGGER.config.write();
+            config.filter = filter;
+            config.pattern = spec.pattern;
+            config.format = Some(format);
+            config.paths = self.paths;
+            config.color = spec.color.unwrap_or(self.color);
+            config.theme = self.theme;
+            config.timestamps = self.timestamps;
+            config.max_level()
+        });
+        log::set_max_level(max_level);
+        Ok(())
+    }
+}
+
+impl<'a> Default for KLoggerBuilder<'a> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use core::sync::atomic::{AtomicBool, Ordering};
+
+    use super::KLoggerBuilder;
+    use sink::test::REGISTRY;
+    use sink::Sink;
+    use {Error, LOGGER};
+
+    struct Written(AtomicBool);
+
+    impl Sink for Written {
+        fn write(&self, _bytes: &[u8]) {
+            self.0.store(true, Ordering::Relaxed);
+        }
+    }
+
+    #[test]
+    fn second_install_changes_nothing() {
+        static WRITTEN: Written = Written(AtomicBool::new(false));
+        let _registry = REGISTRY.lock();
+
+        // Fails if another test installed klogger already, that's fine
+        let _ = KLoggerBuilder::new().filter("off").install();
+        let max_level = log::max_level();
+
+        let second = KLoggerBuilder::new()
+            .filter("trace")
+            .sink(&WRITTEN)
+            .install();
+        assert!(matches!(second, Err(Error::SetLogger(_))));
+        assert_eq!(log::max_level(), max_level);
+        assert_eq!(LOGGER.config.read().max_level(), max_level);
+        sprint!("");
+        assert!(!WRITTEN.0.load(Ordering::Relaxed));
+    }
+}
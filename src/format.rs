@@ -0,0 +1,475 @@
+//! Layout of log lines.
+//!
+//! A format is a template string with tokens in braces that get replaced for
+//! every log record, e.g., the default is
+//! `"{timestamp} [{level}] - {target}: {message}"`. Supported tokens:
+//!
+//! - `{timestamp}`: Time since klogger was installed
+//! - `{level}`: Log level
+//! - `{target}`: Target of the record (usually the module path)
+//! - `{module_path}`: Module the record was logged from
+//! - `{file}`, `{line}`: Source location of the record
+//! - `{cpu}`: Core that logged the record (see `set_cpu_id`)
+//! - `{message}`: The message (followed by the key-value pairs of the record
+//!   in logfmt style with the `kv` feature)
+//!
+//! Use `{{` and `}}` for literal braces. `LOCATION_FORMAT` and
+//! `MODULE_LOCATION_FORMAT` are the default layout with the source location
+//! of the record, `PathTrim` shortens the `{file}` paths.
+//!
+//! `Format::json` writes every record as a JSON object instead (one per
+//! line) with the fields `ts_ns`, `level`, `target`, `module`, `file`,
+//! `line`, `cpu` and `msg` (and `kv` with the `kv` feature).
+//!
+//! With the `binary` feature, `Format::binary` writes compact binary records
+//! (see the `binary` module).
+
+use core::fmt;
+
+use heapless::{String, Vec};
+use log::Record;
+
+use super::{json, ElapsedTime, Error};
+use builder::SourceLocation;
+#[cfg(feature = "kv")]
+use kv;
+use theme::{Color, Painted, Theme};
+
+/// Maximum length of a format template.
+pub const MAX_FORMAT: usize = 128;
+
+/// Maximum number of tokens (including the literal parts) in a template.
+const MAX_TOKENS: usize = 32;
+
+/// The layout klogger used before formats were configurable.
+pub const DEFAULT_FORMAT: &str = "{timestamp} [{level}] - {target}: {message}";
+
+/// The default layout with `file:line` after the target.
+pub const LOCATION_FORMAT: &str = "{timestamp} [{level}] - {target} {file}:{line}: {message}";
+
+/// The default layout with module path and `file:line` after the target.
+pub const MODULE_LOCATION_FORMAT: &str =
+    "{timestamp} [{level}] - {target} {module_path} {file}:{line}: {message}";
+
+/// The default layout with the CPU id after the level (if `cpu` is set) and
+/// the source location after the target.
+pub(crate) fn default_template(cpu: bool, location: SourceLocation) -> String<MAX_FORMAT> {
+    let mut template = String::new();
+    for part in [
+        "{timestamp} [{level}]",
+        if cpu { " cpu{cpu}" } else { "" },
+        " - {target}",
+        match location {
+            SourceLocation::Off => "",
+            SourceLocation::File => " {file}:{line}",
+            SourceLocation::ModuleAndFile => " {module_path} {file}:{line}",
+        },
+        ": {message}",
+    ] {
+        // Can't fail, the longest template is well below MAX_FORMAT
+        let _ = template.push_str(part);
+    }
+    template
+}
+
+/// How much of the source file path the `{file}` token prints.
+///
+/// JSON output always has the full path.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PathTrim {
+    /// The path as given by `file!()`.
+    Full,
+    /// Remove this prefix (e.g., `"kernel/src/"`) if the path starts with
+    /// it (whole components only, `"kernel/src"` doesn't strip
+    /// `kernel/srcfoo/`).
+    StripPrefix(&'static str),
+    /// Only the last N components (e.g., `KeepLast(1)` is the file name,
+    /// `KeepLast(0)` keeps the full path).
+    KeepLast(usize),
+}
+
+impl PathTrim {
+    /// `path` shortened accordingly.
+    pub fn apply(self, path: &str) -> &str {
+        match self {
+            PathTrim::Full => path,
+            PathTrim::StripPrefix(prefix) => match path.strip_prefix(prefix) {
+                Some(rest)
+                    if rest.is_empty()
+                        || prefix.ends_with(['/', '\\'])
+                        || rest.starts_with(['/', '\\']) =>
+                {
+                    rest.trim_start_matches(['/', '\\'])
+                }
+                _ => path,
+            },
+            PathTrim::KeepLast(n) => {
+                match n
+                    .checked_sub(1)
+                    .and_then(|n| path.rmatch_indices(['/', '\\']).nth(n))
+                {
+                    Some((pos, _)) => &path[pos + 1..],
+                    None => path,
+                }
+            }
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Token {
+    /// Part of the template between `start` and `end`.
+    Literal(u8, u8),
+    Timestamp,
+    Level,
+    Target,
+    ModulePath,
+    File,
+    Line,
+    Cpu,
+    Message,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Kind {
+    Template,
+    Json,
+    #[cfg(feature = "binary")]
+    Binary,
+}
+
+/// A parsed format template (or one of the structured outputs).
+#[derive(Debug, Clone)]
+pub struct Format {
+    template: String<MAX_FORMAT>,
+    tokens: Vec<Token, MAX_TOKENS>,
+    kind: Kind,
+}
+
+impl Format {
+    const fn without_template(kind: Kind) -> Format {
+        Format {
+            template: String::new(),
+            tokens: Vec::new(),
+            kind,
+        }
+    }
+
+    /// Write records as JSON objects.
+    pub const fn json() -> Format {
+        Format::without_template(Kind::Json)
+    }
+
+    /// Write records as binary frames (see the `binary` module).
+    #[cfg(feature = "binary")]
+    pub const fn binary() -> Format {
+        Format::without_template(Kind::Binary)
+    }
+
+    /// Are records written as binary frames (instead of text)?
+    #[cfg(feature = "binary")]
+    pub(crate) fn is_binary(&self) -> bool {
+        self.kind == Kind::Binary
+    }
+
+    /// Does the format print the CPU id?
+    pub(crate) fn needs_cpu(&self) -> bool {
+        self.kind == Kind::Json || self.tokens.contains(&Token::Cpu)
+    }
+
+    /// Parse `template`, fails with `Error::InvalidFormat` for unknown tokens
+    /// or unbalanced braces.
+    pub fn parse(template: &str) -> Result<Format, Error> {
+        let mut format = Format::without_template(Kind::Template);
+        format
+            .template
+            .push_str(template)
+            .map_err(|_| Error::InvalidFormat)?;
+
+        let bytes = template.as_bytes();
+        let mut literal_start = 0;
+        let mut i = 0;
+        while i < bytes.len() {
+            let token = match bytes[i] {
+                // Escaped braces become a literal with just the brace
+                b'{' | b'}' if bytes.get(i + 1) == Some(&bytes[i]) => {
+                    format.push_literal(literal_start, i + 1)?;
+                    i += 2;
+                    literal_start = i;
+                    continue;
+                }
+                b'{' => {
+                    let len = template[i..].find('}').ok_or(Error::InvalidFormat)?;
+                    let token = match &template[i + 1..i + len] {
+                        "timestamp" => Token::Timestamp,
+                        "level" => Token::Level,
+                        "target" => Token::Target,
+                        "module_path" => Token::ModulePath,
+                        "file" => Token::File,
+                        "line" => Token::Line,
+                        "cpu" => Token::Cpu,
+                        "message" => Token::Message,
+                        _ => return Err(Error::InvalidFormat),
+                    };
+                    format.push_literal(literal_start, i)?;
+                    i += len + 1;
+                    literal_start = i;
+                    token
+                }
+                b'}' => return Err(Error::InvalidFormat),
+                _ => {
+                    i += 1;
+                    continue;
+                }
+            };
+            format.push(token)?;
+        }
+        format.push_literal(literal_start, bytes.len())?;
+
+        Ok(format)
+    }
+
+    fn push(&mut self, token: Token) -> Result<(), Error> {
+        self.tokens.push(token).map_err(|_| Error::InvalidFormat)
+    }
+
+    fn push_literal(&mut self, start: usize, end: usize) -> Result<(), Error> {
+        if start < end {
+            // Can't overflow u8, the template is at most MAX_FORMAT bytes
+            self.push(Token::Literal(start as u8, end as u8))
+        } else {
+            Ok(())
+        }
+    }
+
+    /// Write `record` (without line break) according to the format, in the
+    /// colours of `theme` (if any, JSON is never coloured).
+    ///
+    /// `cpu` is the core that logged the record (if `needs_cpu`).
+    pub(crate) fn write<W: fmt::Write>(
+        &self,
+        w: &mut W,
+        record: &Record,
+        elapsed: &ElapsedTime,
+        paths: PathTrim,
+        cpu: Option<u32>,
+        theme: Option<&Theme>,
+    ) -> fmt::Result {
+        if self.kind == Kind::Json {
+            return json::write_record(w, record, elapsed, cpu);
+        }
+        let color = |pick: fn(&Theme) -> Color| theme.map_or(Color::Default, pick);
+        for token in self.tokens.iter() {
+            match *token {
+                Token::Literal(start, end) => {
+                    w.write_str(&self.template[start as usize..end as usize])?
+                }
+                Token::Timestamp => write!(w, "{}", Painted(color(|t| t.timestamp), elapsed))?,
+                Token::Level => write!(
+                    w,
+                    "{}",
+                    Painted(
+                        theme.map_or(Color::Default, |t| t.level(record.level())),
+                        format_args!("{:5}", record.level())
+                    )
+                )?,
+                Token::Target => write!(w, "{}", Painted(color(|t| t.target), record.target()))?,
+                Token::ModulePath => w.write_str(record.module_path().unwrap_or("?"))?,
+                Token::File => w.write_str(record.file().map_or("?", |f| paths.apply(f)))?,
+                Token::Line => match record.line() {
+                    Some(line) => write!(w, "{}", line)?,
+                    None => w.write_str("?")?,
+                },
+                Token::Cpu => match cpu {
+                    Some(cpu) => write!(
+                        w,
+                        "{}",
+                        Painted(theme.map_or(Color::Default, |t| t.cpu(cpu)), cpu)
+                    )?,
+                    None => w.write_str("?")?,
+                },
+                Token::Message => {
+                    write!(w, "{}", Painted(color(|t| t.message), record.args()))?;
+                    #[cfg(feature = "kv")]
+                    kv::write_logfmt(w, record.key_values())?;
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+impl Default for Format {
+    fn default() -> Format {
+        Format::parse(DEFAULT_FORMAT).expect("default format is valid")
+    }
+}
+
+/// `Format::default`, parsed once (for records logged before `install`).
+pub(crate) fn default() -> &'static Format {
+    static DEFAULT: spin::Once<Format> = spin::Once::new();
+    DEFAULT.call_once(Format::default)
+}
+
+#[cfg(test)]
+mod test {
+    use heapless::String;
+    use log::{Level, Record};
+
+    use super::{
+        default_template, Format, PathTrim, DEFAULT_FORMAT, LOCATION_FORMAT, MODULE_LOCATION_FORMAT,
+    };
+    use {ElapsedTime, Error, SourceLocation};
+
+    fn render_trimmed(template: &str, record: &Record, paths: PathTrim) -> String<256> {
+        let mut out = String::new();
+        Format::parse(template)
+            .unwrap()
+            .write(
+                &mut out,
+                record,
+                &ElapsedTime::Nanoseconds(1234),
+                paths,
+                Some(3),
+                None,
+            )
+            .unwrap();
+        out
+    }
+
+    fn render(template: &str, record: &Record) -> String<256> {
+        render_trimmed(template, record, PathTrim::Full)
+    }
+
+    #[test]
+    fn default_format() {
+        let line = render(
+            DEFAULT_FORMAT,
+            &Record::builder()
+                .args(format_args!("hello {}", 42))
+                .level(Level::Info)
+                .target("crate1::mod1")
+                .build(),
+        );
+        assert_eq!(line, "      1234 [INFO ] - crate1::mod1: hello 42");
+    }
+
+    #[test]
+    fn source_location() {
+        let line = render(
+            "{level} {module_path} {file}:{line} {{{message}}}",
+            &Record::builder()
+                .args(format_args!("msg"))
+                .level(Level::Warn)
+                .module_path(Some("crate1::mod1"))
+                .file(Some("src/mod1.rs"))
+                .line(Some(42))
+                .build(),
+        );
+        assert_eq!(line, "WARN  crate1::mod1 src/mod1.rs:42 {msg}");
+    }
+
+    #[test]
+    fn location_formats() {
+        let record = Record::builder()
+            .args(format_args!("up"))
+            .level(Level::Info)
+            .target("net")
+            .module_path(Some("kernel::net"))
+            .file(Some("kernel/src/net/mod.rs"))
+            .line(Some(7))
+            .build();
+        assert_eq!(
+            render(LOCATION_FORMAT, &record),
+            "      1234 [INFO ] - net kernel/src/net/mod.rs:7: up"
+        );
+        assert_eq!(
+            render_trimmed(MODULE_LOCATION_FORMAT, &record, PathTrim::KeepLast(2)),
+            "      1234 [INFO ] - net kernel::net net/mod.rs:7: up"
+        );
+        assert_eq!(
+            render(&default_template(true, SourceLocation::File), &record),
+            "      1234 [INFO ] cpu3 - net kernel/src/net/mod.rs:7: up"
+        );
+    }
+
+    #[test]
+    fn default_templates() {
+        assert_eq!(default_template(false, SourceLocation::Off), DEFAULT_FORMAT);
+        assert_eq!(
+            default_template(false, SourceLocation::File),
+            LOCATION_FORMAT
+        );
+        assert_eq!(
+            default_template(false, SourceLocation::ModuleAndFile),
+            MODULE_LOCATION_FORMAT
+        );
+        assert!(Format::parse(&default_template(true, SourceLocation::ModuleAndFile)).is_ok());
+    }
+
+    #[test]
+    fn trim_paths() {
+        let path = "kernel/src/net/mod.rs";
+        assert_eq!(PathTrim::Full.apply(path), path);
+        assert_eq!(
+            PathTrim::StripPrefix("kernel/src").apply(path),
+            "net/mod.rs"
+        );
+        assert_eq!(
+            PathTrim::StripPrefix("kernel/src/").apply(path),
+            "net/mod.rs"
+        );
+        assert_eq!(PathTrim::StripPrefix("lib/").apply(path), path);
+        assert_eq!(
+            PathTrim::StripPrefix("kernel/src").apply("kernel/srcfoo/x.rs"),
+            "kernel/srcfoo/x.rs"
+        );
+        assert_eq!(PathTrim::KeepLast(1).apply(path), "mod.rs");
+        assert_eq!(PathTrim::KeepLast(3).apply(path), "src/net/mod.rs");
+        assert_eq!(PathTrim::KeepLast(10).apply(path), path);
+        assert_eq!(PathTrim::KeepLast(0).apply(path), path);
+        assert_eq!(PathTrim::KeepLast(1).apply("src\\main.rs"), "main.rs");
+    }
+
+    #[test]
+    #[cfg(feature = "colors")]
+    fn colored_parts() {
+        use theme::{Color, Theme, CPU_COLORS};
+
+        let mut out: String<256> = String::new();
+        Format::parse("{level} {cpu}: {target}")
+            .unwrap()
+            .write(
+                &mut out,
+                &Record::builder()
+                    .args(format_args!("msg"))
+                    .level(Level::Error)
+                    .target("pci")
+                    .build(),
+                &ElapsedTime::Undetermined,
+                PathTrim::Full,
+                Some(7),
+                Some(&Theme {
+                    target: Color::Rgb(0, 128, 255),
+                    cpus: &CPU_COLORS,
+                    ..Theme::DARK
+                }),
+            )
+            .unwrap();
+        assert_eq!(
+            out,
+            "\x1b[38;5;202mERROR\x1b[39m \x1b[95m7\x1b[39m: \x1b[38;2;0;128;255mpci\x1b[39m"
+        );
+    }
+
+    #[test]
+    fn invalid_templates() {
+        for template in ["{", "{unknown}", "}", "{message"] {
+            assert!(
+                matches!(Format::parse(template), Err(Error::InvalidFormat)),
+                "{}",
+                template
+            );
+        }
+    }
+}
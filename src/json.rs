@@ -0,0 +1,132 @@
+//! JSON-lines output, one object per log record.
+//!
+//! Everything is written directly to the output, strings are escaped on the
+//! fly so we don't need any buffers.
+
+use core::fmt;
+use core::fmt::Write;
+
+use log::Record;
+
+use super::ElapsedTime;
+#[cfg(feature = "kv")]
+use kv;
+
+/// Escapes everything written to it for use in a JSON string.
+pub(crate) struct Escaped<'a, W: fmt::Write>(pub &'a mut W);
+
+impl<'a, W: fmt::Write> fmt::Write for Escaped<'a, W> {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        let mut start = 0;
+        for (i, b) in s.bytes().enumerate() {
+            let escape = match b {
+                b'"' => "\\\"",
+                b'\\' => "\\\\",
+                b'\n' => "\\n",
+                b'\r' => "\\r",
+                b'\t' => "\\t",
+                0x08 => "\\b",
+                0x0c => "\\f",
+                0x00..=0x1f => "",
+                _ => continue,
+            };
+            self.0.write_str(&s[start..i])?;
+            if escape.is_empty() {
+                write!(self.0, "\\u{:04x}", b)?;
+            } else {
+                self.0.write_str(escape)?;
+            }
+            start = i + 1;
+        }
+        self.0.write_str(&s[start..])
+    }
+}
+
+/// Write `value` as a JSON string.
+pub(crate) fn write_string<W: fmt::Write, T: fmt::Display>(w: &mut W, value: T) -> fmt::Result {
+    w.write_char('"')?;
+    write!(Escaped(w), "{}", value)?;
+    w.write_char('"')
+}
+
+/// Write `value` as a JSON string, or `null` if there is none.
+fn write_optional_string<W: fmt::Write>(w: &mut W, value: Option<&str>) -> fmt::Result {
+    match value {
+        Some(value) => write_string(w, value),
+        None => w.write_str("null"),
+    }
+}
+
+/// Write `record` as a JSON object (without line break).
+///
+/// The timestamp is `ts_ns` if we know the time, `ts_cycles` if we only have
+/// the counter value and `null` otherwise.
+pub(crate) fn write_record<W: fmt::Write>(
+    w: &mut W,
+    record: &Record,
+    elapsed: &ElapsedTime,
+    cpu: Option<u32>,
+) -> fmt::Result {
+    match elapsed {
+        ElapsedTime::Nanoseconds(ns) => write!(w, "{{\"ts_ns\":{}", ns)?,
+        ElapsedTime::Cycles(cycles) => write!(w, "{{\"ts_cycles\":{}", cycles)?,
+        ElapsedTime::Undetermined => w.write_str("{\"ts_ns\":null")?,
+    }
+    write!(w, ",\"level\":\"{}\",\"target\":", record.level())?;
+    write_string(w, record.target())?;
+    w.write_str(",\"module\":")?;
+    write_optional_string(w, record.module_path())?;
+    w.write_str(",\"file\":")?;
+    write_optional_string(w, record.file())?;
+    match record.line() {
+        Some(line) => write!(w, ",\"line\":{}", line)?,
+        None => w.write_str(",\"line\":null")?,
+    }
+    match cpu {
+        Some(cpu) => write!(w, ",\"cpu\":{}", cpu)?,
+        None => w.write_str(",\"cpu\":null")?,
+    }
+    w.write_str(",\"msg\":")?;
+    write_string(w, record.args())?;
+    #[cfg(feature = "kv")]
+    kv::write_json(w, record.key_values())?;
+    w.write_char('}')
+}
+
+#[cfg(test)]
+mod test {
+    use heapless::String;
+    use log::{Level, Record};
+
+    use super::{write_record, write_string};
+    use ElapsedTime;
+
+    #[test]
+    fn escaping() {
+        let mut out: String<64> = String::new();
+        write_string(&mut out, "a \"b\" \\ c\r\n\t\x01 ü").unwrap();
+        assert_eq!(out, r#""a \"b\" \\ c\r\n\t\u0001 ü""#);
+    }
+
+    #[test]
+    fn record() {
+        let mut out: String<256> = String::new();
+        write_record(
+            &mut out,
+            &Record::builder()
+                .args(format_args!("got \"{}\"", 42))
+                .level(Level::Warn)
+                .target("net::tcp")
+                .module_path(Some("net::tcp"))
+                .line(Some(7))
+                .build(),
+            &ElapsedTime::Nanoseconds(1234),
+            Some(3),
+        )
+        .unwrap();
+        assert_eq!(
+            out,
+            r#"{"ts_ns":1234,"level":"WARN","target":"net::tcp","module":"net::tcp","file":null,"line":7,"cpu":3,"msg":"got \"42\""}"#
+        );
+    }
+}
@@ -0,0 +1,123 @@
+//! Key-value pairs of log records (`info!(irq = 5; "enabled")`).
+//!
+//! Text output appends them to the message in logfmt style
+//! (`enabled irq=5`), JSON output puts them in a `kv` object.
+
+use core::fmt;
+use core::fmt::Write;
+
+use log::kv::{Error, Key, Source, Value, VisitSource};
+
+use json::{write_string, Escaped};
+
+/// Finds out if a logfmt value needs to be quoted.
+struct NeedsQuotes(bool);
+
+impl fmt::Write for NeedsQuotes {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        self.0 |= s
+            .bytes()
+            .any(|b| b <= b' ' || b == b'=' || b == b'"' || b == b'\\');
+        Ok(())
+    }
+}
+
+struct Logfmt<'a, W: fmt::Write>(&'a mut W);
+
+impl<'a, 'kvs, W: fmt::Write> VisitSource<'kvs> for Logfmt<'a, W> {
+    fn visit_pair(&mut self, key: Key<'kvs>, value: Value<'kvs>) -> Result<(), Error> {
+        // Values are formatted twice, once to check if we need quotes
+        let mut check = NeedsQuotes(false);
+        write!(check, "{}", value)?;
+        write!(self.0, " {}=", key)?;
+        if check.0 {
+            write_string(self.0, value)?;
+        } else {
+            write!(self.0, "{}", value)?;
+        }
+        Ok(())
+    }
+}
+
+struct Json<'a, W: fmt::Write> {
+    w: &'a mut W,
+    first: bool,
+}
+
+impl<'a, 'kvs, W: fmt::Write> VisitSource<'kvs> for Json<'a, W> {
+    fn visit_pair(&mut self, key: Key<'kvs>, value: Value<'kvs>) -> Result<(), Error> {
+        self.w
+            .write_str(if self.first { ",\"kv\":{" } else { "," })?;
+        self.first = false;
+        write_string(self.w, key)?;
+        self.w.write_char(':')?;
+        if let Some(b) = value.to_bool() {
+            write!(self.w, "{}", b)?;
+        } else if let Some(n) = value.to_u64() {
+            write!(self.w, "{}", n)?;
+        } else if let Some(n) = value.to_i64() {
+            write!(self.w, "{}", n)?;
+        } else if let Some(n) = value.to_f64().filter(|n| n.is_finite()) {
+            write!(self.w, "{}", n)?;
+        } else {
+            self.w.write_char('"')?;
+            write!(Escaped(self.w), "{}", value)?;
+            self.w.write_char('"')?;
+        }
+        Ok(())
+    }
+}
+
+/// Write the pairs of `source` as ` key=value`.
+pub(crate) fn write_logfmt<W: fmt::Write>(w: &mut W, source: &dyn Source) -> fmt::Result {
+    source.visit(&mut Logfmt(w)).map_err(|_| fmt::Error)
+}
+
+/// Write the pairs of `source` as `,"kv":{...}` (nothing if there are none).
+pub(crate) fn write_json<W: fmt::Write>(w: &mut W, source: &dyn Source) -> fmt::Result {
+    let mut json = Json { w, first: true };
+    source.visit(&mut json).map_err(|_| fmt::Error)?;
+    if !json.first {
+        json.w.write_char('}')?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use heapless::String;
+    use log::kv::Source;
+
+    use super::{write_json, write_logfmt};
+
+    fn pairs() -> [(&'static str, log::kv::Value<'static>); 4] {
+        [
+            ("dev", "00:1f.0".into()),
+            ("irq", 5.into()),
+            ("name", "ahci ctrl".into()),
+            ("ok", true.into()),
+        ]
+    }
+
+    #[test]
+    fn logfmt() {
+        let mut out: String<128> = String::new();
+        write_logfmt(&mut out, &pairs() as &dyn Source).unwrap();
+        assert_eq!(out, r#" dev=00:1f.0 irq=5 name="ahci ctrl" ok=true"#);
+    }
+
+    #[test]
+    fn json() {
+        let mut out: String<128> = String::new();
+        write_json(&mut out, &pairs() as &dyn Source).unwrap();
+        assert_eq!(
+            out,
+            r#","kv":{"dev":"00:1f.0","irq":5,"name":"ahci ctrl","ok":true}"#
+        );
+
+        let mut out: String<128> = String::new();
+        let none: [(&str, i32); 0] = [];
+        write_json(&mut out, &none as &dyn Source).unwrap();
+        assert_eq!(out, "");
+    }
+}
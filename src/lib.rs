@@ -2,22 +2,46 @@
 #![crate_name = "klogger"]
 #![crate_type = "lib"]
 
+#[cfg(feature = "alloc")]
+extern crate alloc;
 #[cfg(not(target_os = "none"))]
 extern crate core;
 extern crate heapless;
 #[cfg(all(target_arch = "aarch64", target_os = "none"))]
 extern crate pl011_qemu;
 
-use core::convert::TryInto;
 use core::fmt;
+use core::fmt::Write;
+use core::mem;
 use core::ops;
+use core::ptr;
+use core::sync::atomic::{AtomicPtr, Ordering};
 
 #[macro_use]
 pub mod macros;
+#[cfg(feature = "binary")]
+pub mod binary;
+pub mod builder;
+pub mod format;
+mod json;
+#[cfg(feature = "kv")]
+mod kv;
+mod lock;
+#[cfg(feature = "panic")]
+pub mod panic;
+mod pattern;
+pub mod ringbuf;
+pub mod sink;
+pub mod theme;
+mod time;
 
 extern crate log;
+#[cfg(feature = "colors")]
 extern crate termcodes;
 
+#[cfg(test)]
+extern crate proptest;
+
 #[cfg(any(
     feature = "use_ioports",
     all(target_arch = "x86_64", target_os = "none")
@@ -36,23 +60,164 @@ compile_error!("ioports are not supported on aarch64");
 #[path = "arch/unix.rs"]
 mod arch;
 
-use heapless::{String, Vec};
+pub use arch::Console;
+pub use builder::{ColorMode, KLoggerBuilder, SourceLocation, TimestampMode};
+pub use format::{Format, PathTrim};
+pub use lock::{dropped_nested, emergency, in_emergency};
+pub use ringbuf::RingBuffer;
+pub use sink::{add_filtered_sink, add_sink, clear_sinks, Sink};
+pub use theme::Theme;
+
 use log::{Level, LevelFilter, Metadata, Record, SetLoggerError};
-use termcodes::color; // type level integer used to specify capacity
+use pattern::Pattern;
+use sink::SinkWriter;
+use time::CyclesToNs;
+
+/// Errors returned when configuring klogger.
+#[derive(Debug)]
+pub enum Error {
+    /// All `sink::MAX_SINKS` slots are already in use.
+    TooManySinks,
+    /// The filter has more than `MAX_DIRECTIVES` directives.
+    TooManyDirectives,
+    /// A module path in the filter is longer than `MAX_DIRECTIVE_NAME`.
+    DirectiveNameTooLong,
+    /// The message pattern in the filter is longer than `MAX_PATTERN`.
+    PatternTooLong,
+    /// The format template has unknown tokens, unbalanced braces or is
+    /// too long.
+    InvalidFormat,
+    /// The output (serial port) is out of range for the architecture.
+    InvalidOutput(u64),
+    /// Another logger was installed already.
+    SetLogger(SetLoggerError),
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Error::TooManySinks => write!(f, "can't register more than {} sinks", sink::MAX_SINKS),
+            Error::TooManyDirectives => {
+                write!(f, "filter has more than {} directives", MAX_DIRECTIVES)
+            }
+            Error::DirectiveNameTooLong => write!(
+                f,
+                "module path in filter is longer than {} bytes",
+                MAX_DIRECTIVE_NAME
+            ),
+            Error::PatternTooLong => write!(
+                f,
+                "message pattern is longer than {} bytes",
+                pattern::MAX_PATTERN
+            ),
+            Error::InvalidFormat => write!(f, "invalid format template"),
+            Error::InvalidOutput(output) => write!(f, "invalid output {:#x}", output),
+            Error::SetLogger(e) => write!(f, "{}", e),
+        }
+    }
+}
+
+impl From<SetLoggerError> for Error {
+    fn from(e: SetLoggerError) -> Self {
+        Error::SetLogger(e)
+    }
+}
 
 /// Global lock to protect serial line from concurrent printing.
+///
+/// klogger disables interrupts while holding it, code that takes it
+/// directly should do the same.
 pub static SERIAL_LINE_MUTEX: spin::Mutex<bool> = spin::Mutex::new(false);
 
-#[derive(Debug)]
+/// How many directives a filter can have (unlimited with the `alloc`
+/// feature).
+#[cfg(not(feature = "more_directives"))]
+pub const MAX_DIRECTIVES: usize = 8;
+#[cfg(feature = "more_directives")]
+pub const MAX_DIRECTIVES: usize = 64;
+
+/// How long the module path of a directive can be (unlimited with the
+/// `alloc` feature).
+#[cfg(not(feature = "long_directive_names"))]
+pub const MAX_DIRECTIVE_NAME: usize = 64;
+#[cfg(feature = "long_directive_names")]
+pub const MAX_DIRECTIVE_NAME: usize = 256;
+
+#[cfg(not(feature = "alloc"))]
+type DirectiveName = heapless::String<MAX_DIRECTIVE_NAME>;
+#[cfg(feature = "alloc")]
+type DirectiveName = alloc::string::String;
+
+#[cfg(not(feature = "alloc"))]
+type Directives = heapless::Vec<Directive, MAX_DIRECTIVES>;
+#[cfg(feature = "alloc")]
+type Directives = alloc::vec::Vec<Directive>;
+
+#[derive(Debug, Clone)]
 pub struct Directive {
-    name: Option<String<64>>,
+    name: Option<DirectiveName>,
     level: LevelFilter,
 }
 
-//unsafe impl ArrayLength<Directive> for Directive;
+impl Directive {
+    #[cfg(not(feature = "alloc"))]
+    fn new(name: Option<&str>, level: LevelFilter) -> Result<Directive, Error> {
+        let name = match name {
+            None => None,
+            Some(name) => {
+                let mut owned = DirectiveName::new();
+                owned
+                    .push_str(name)
+                    .map_err(|_| Error::DirectiveNameTooLong)?;
+                Some(owned)
+            }
+        };
+        Ok(Directive { name, level })
+    }
+
+    #[cfg(feature = "alloc")]
+    fn new(name: Option<&str>, level: LevelFilter) -> Result<Directive, Error> {
+        Ok(Directive {
+            name: name.map(DirectiveName::from),
+            level,
+        })
+    }
+}
+
+/// Add `directive` to `directives` (fails if there is no space left).
+///
+/// A directive for a module that is in `directives` already overrides the
+/// existing one in place, so that always fits.
+#[cfg(not(feature = "alloc"))]
+fn push_directive(directives: &mut Directives, directive: Directive) -> Result<(), Error> {
+    if let Some(existing) = directives.iter_mut().find(|d| d.name == directive.name) {
+        existing.level = directive.level;
+        return Ok(());
+    }
+    directives
+        .push(directive)
+        .map_err(|_| Error::TooManyDirectives)
+}
+
+/// Add `directive` to `directives`.
+#[cfg(feature = "alloc")]
+fn push_directive(directives: &mut Directives, directive: Directive) -> Result<(), Error> {
+    directives.push(directive);
+    Ok(())
+}
+
+/// Everything in a filter spec besides the directives.
+#[derive(Debug, Default)]
+struct Spec {
+    /// The message pattern (the part after the '/').
+    pattern: Option<Pattern>,
+    /// Set by a `color=always|never|auto` directive.
+    color: Option<ColorMode>,
+}
 
+/// What we know about the time stamp counter, detected once by `install`.
 #[derive(Debug)]
-struct KLogger {
+struct Clock {
     /// Do we even have a TSC?
     ///
     /// If not bad.
@@ -63,14 +228,52 @@ struct KLogger {
     has_invariant_tsc: bool,
     /// Point in time when this Klogger got initialized
     tsc_start: u64,
-    /// Frequency in Hz
+    /// Converts TSC cycles to ns.
     ///
-    /// Sometimes we can't figure this out (yet)
-    tsc_frequency: Option<u64>,
+    /// Sometimes we can't figure out the frequency (yet)
+    tsc_to_ns: Option<CyclesToNs>,
+}
+
+/// Settings of KLogger that can be changed at runtime.
+#[derive(Debug)]
+struct Config {
     /// Filter(s) used by Klogger.
     ///
     /// Use module name or log level or both for filtering.
-    filter: Vec<Directive, 8>,
+    filter: Directives,
+    /// Only log records whose message matches.
+    pattern: Option<Pattern>,
+    /// Layout of the log lines (set by `install`).
+    format: Option<Format>,
+    /// How much of the source file paths we print.
+    paths: PathTrim,
+    /// Do we print colour codes?
+    color: ColorMode,
+    /// Colours used if we do.
+    theme: Theme,
+    /// What we print as timestamp.
+    timestamps: TimestampMode,
+}
+
+impl Config {
+    /// Logs nothing until `install` sets the filter.
+    const DEFAULT: Config = Config {
+        filter: Directives::new(),
+        pattern: None,
+        format: None,
+        paths: PathTrim::Full,
+        color: ColorMode::Auto,
+        theme: Theme::DARK,
+        timestamps: TimestampMode::Auto,
+    };
+}
+
+#[derive(Debug)]
+struct KLogger {
+    /// Set once klogger is installed.
+    clock: spin::Once<Clock>,
+    /// Can be changed while other cores are logging.
+    config: spin::RwLock<Config>,
 }
 
 enum ElapsedTime {
@@ -90,42 +293,90 @@ impl fmt::Display for ElapsedTime {
     }
 }
 
-impl KLogger {
-    /// Time in nano seconds since KLogger init.
-    fn elapsed(&self) -> ElapsedTime {
-        if self.has_tsc {
-            let cur = arch::get_timestamp();
+impl Clock {
+    /// Figure out what kind of TSC we have and how fast it is.
+    fn detect() -> Clock {
+        let has_tsc = arch::has_tsc();
+        let has_invariant_tsc = arch::has_invariant_tsc();
+        let tsc_start = if has_tsc { arch::get_timestamp() } else { 0 };
+
+        let tsc_frequency_hz: Option<u64> = arch::get_tsc_frequency_hz();
+
+        // Check if we run in a VM and the hypervisor can give us the TSC frequency
+        let vmm_tsc_frequency_hz: Option<u64> = arch::get_vmm_tsc_frequency_hz();
+
+        let tsc_frequency = if tsc_frequency_hz.is_some() {
+            tsc_frequency_hz
+        } else if vmm_tsc_frequency_hz.is_some() {
+            vmm_tsc_frequency_hz
+        } else if has_invariant_tsc {
+            // Nobody tells us, measure it against the HPET or PIT
+            arch::calibrate_tsc_frequency_hz()
+        } else {
+            None
+        };
+
+        // Another way that segfaults in KVM:
+        // The scalable bus frequency is encoded in the bit field MSR_PLATFORM_INFO[15:8]
+        // and the nominal TSC frequency can be determined by multiplying this number
+        // by a bus speed of 100 MHz.
+        //tsc_frequency =
+        //    ((x86::msr::rdmsr(x86::msr::MSR_PLATFORM_INFO) >> 8) & 0xff) * 1000000;
 
-            if self.has_invariant_tsc && self.tsc_frequency.is_some() {
-                let elapsed_cycles = cur - self.tsc_start;
-                let _tsc_frequency_hz = self.tsc_frequency.unwrap_or(1); // This won't fail, checked by if above
+        Clock {
+            has_tsc,
+            has_invariant_tsc,
+            tsc_start,
+            tsc_to_ns: tsc_frequency.and_then(CyclesToNs::new),
+        }
+    }
 
-                // Basic is: let ns = elapsed_cycles / (tsc_frequency / NS_PER_SEC);
-                // But we avoid removing all precision with division:
-                // TODO: fix overflow with * NS_PER_SEC
-                //let ns = (elapsed_cycles * NS_PER_SEC) / tsc_frequency_hz;
-                let ns = elapsed_cycles;
+    /// Time in nano seconds since KLogger init.
+    fn elapsed(&self, mode: TimestampMode) -> ElapsedTime {
+        if self.has_tsc && mode != TimestampMode::Off {
+            let cur = arch::get_timestamp();
 
-                ElapsedTime::Nanoseconds(ns)
-            } else {
+            match self.tsc_to_ns {
+                Some(conv) if self.has_invariant_tsc && mode == TimestampMode::Auto => {
+                    ElapsedTime::Nanoseconds(conv.to_ns(cur.saturating_sub(self.tsc_start)))
+                }
                 // We can't convert cycles to a time
-                ElapsedTime::Cycles(cur)
+                _ => ElapsedTime::Cycles(cur),
             }
         } else {
             // We don't know
             ElapsedTime::Undetermined
         }
     }
+}
 
+impl Config {
     /// Returns the maximum `LevelFilter` that this filter instance is
     /// configured to output.
-    pub fn filter(&self) -> LevelFilter {
-        return self
-            .filter
+    fn max_level(&self) -> LevelFilter {
+        self.filter
             .iter()
             .map(|d| d.level)
             .max()
-            .unwrap_or(LevelFilter::Off);
+            .unwrap_or(LevelFilter::Off)
+    }
+}
+
+impl KLogger {
+    /// The config, `None` in emergency mode if it's being changed right now
+    /// (we might have panicked doing that).
+    fn read_config(&self) -> Option<spin::RwLockReadGuard<'_, Config>> {
+        if in_emergency() {
+            self.config.try_read()
+        } else {
+            Some(self.config.read())
+        }
+    }
+
+    fn elapsed(&self, mode: TimestampMode) -> ElapsedTime {
+        self.clock
+            .r#try()
+            .map_or(ElapsedTime::Undetermined, |clock| clock.elapsed(mode))
     }
 }
 
@@ -134,59 +385,98 @@ impl log::Log for KLogger {
         let level = metadata.level();
         let target = metadata.target();
 
-        enabled(&self.filter, level, target)
+        // Without the config only the max level applies
+        self.read_config()
+            .is_none_or(|config| enabled(&config.filter, level, target))
     }
 
     fn log(&self, record: &Record) {
-        if self.enabled(record.metadata()) {
-            let color = match record.level() {
-                Level::Error => color::AnsiValue(202),
-                Level::Warn => color::AnsiValue(167),
-                Level::Info => color::AnsiValue(136),
-                Level::Debug => color::AnsiValue(64),
-                Level::Trace => color::AnsiValue(32),
-            };
+        // We hold the config until the record is written and `record.args()`
+        // is formatted meanwhile, so it must not change the config (e.g.,
+        // with `set_filter`)
+        let guard = self.read_config();
+        if let Some(config) = &guard {
+            if !enabled(&config.filter, record.level(), record.target()) {
+                return;
+            }
+        }
+        let config = guard.as_deref().unwrap_or(&FALLBACK_CONFIG);
+
+        // Patterns are matched against the formatted message
+        if let Some(pattern) = &config.pattern {
+            let mut matcher = pattern.matcher();
+            let _ = write!(matcher, "{}", record.args());
+            if !matcher.is_match() {
+                return;
+            }
+        }
 
-            sprintln!(
-                "{}{}{} [{}{:5}{}] - {}: {}{}{}",
-                color::Fg(color::LightYellow),
-                self.elapsed(),
-                color::Fg(color::Reset),
-                color::Fg(color),
-                record.level(),
-                color::Fg(color::Reset),
-                record.target(),
-                color::Fg(color::LightWhite),
-                record.args(),
-                color::Fg(color::Reset),
+        let (color, theme, timestamps, paths) =
+            (config.color, &config.theme, config.timestamps, config.paths);
+        let format = config.format.as_ref().unwrap_or_else(|| format::default());
+        let elapsed = self.elapsed(timestamps);
+        let line_lock = match lock::line() {
+            Some(lock) => lock,
+            // Logged while formatting a record on this core
+            None => return,
+        };
+        let cpu = if format.needs_cpu() {
+            Some(line_lock.cpu())
+        } else {
+            None
+        };
+        // Sink patterns are matched against the formatted message as well
+        let message = Some(record.args());
+        sink::for_each_enabled(record.metadata(), message, |sink, sink_color| {
+            #[cfg(feature = "binary")]
+            {
+                if format.is_binary() {
+                    return binary::write_record(sink, record, &elapsed);
+                }
+            }
+            let colored = sink_color.unwrap_or(color).enabled();
+            let mut w = SinkWriter(sink);
+            let _ = format.write(
+                &mut w,
+                record,
+                &elapsed,
+                paths,
+                cpu,
+                colored.then_some(theme),
             );
-        }
+            let _ = w.write_str("\r\n");
+        });
     }
 
-    fn flush(&self) {}
+    fn flush(&self) {
+        sink::flush();
+    }
 }
 
-static mut LOGGER: KLogger = KLogger {
-    has_tsc: false,
-    has_invariant_tsc: false,
-    tsc_start: 0,
-    tsc_frequency: None,
-    filter: Vec::new(),
+static LOGGER: KLogger = KLogger {
+    clock: spin::Once::new(),
+    config: spin::RwLock::new(Config::DEFAULT),
 };
 
+/// What we use in emergency mode if the config can't be read.
+static FALLBACK_CONFIG: Config = Config::DEFAULT;
+
 /// A writer for the serial line. It holds a lock so
 /// multiple cores/threads can print at the same time.
+///
+/// Interrupts are disabled until it's dropped. If the core is printing
+/// already (e.g., `sprintln!` in a `Display` impl that is being logged), the
+/// output is dropped (see `dropped_nested`).
 pub struct Writer<'a> {
     /// Lock on the serial line, it is implicitly released on a drop.
-    #[allow(dead_code)]
-    line_lock: spin::MutexGuard<'a, bool>,
+    line_lock: Option<lock::LineGuard<'a>>,
 }
 
 impl<'a> Writer<'a> {
     /// Obtain a logger for the specified module.
     pub fn get_module(module: &str) -> Writer<'a> {
         use core::fmt::Write;
-        let line_lock = SERIAL_LINE_MUTEX.lock();
+        let line_lock = lock::line();
         let mut ret = Writer { line_lock };
         write!(&mut ret, "[{}] ", module).expect("Writer");
         ret
@@ -194,7 +484,7 @@ impl<'a> Writer<'a> {
 
     /// Obtain a logger.
     pub fn get() -> Writer<'a> {
-        let line_lock = SERIAL_LINE_MUTEX.lock();
+        let line_lock = lock::line();
         Writer { line_lock }
     }
 }
@@ -214,8 +504,8 @@ impl<'a> ops::Drop for Writer<'a> {
 impl<'a> fmt::Write for Writer<'a> {
     /// Write stuff to serial out.
     fn write_str(&mut self, s: &str) -> fmt::Result {
-        unsafe {
-            arch::puts(s);
+        if self.line_lock.is_some() {
+            sink::write(s.as_bytes());
         }
         Ok(())
     }
@@ -225,6 +515,8 @@ impl<'a> fmt::Write for Writer<'a> {
 ///
 /// It's used by sprint at the moment. It can also be useful as part of panics handlers
 /// where we really want to print in all circumstances.
+///
+/// Output is dropped if another core is in emergency mode (see `emergency`).
 pub struct WriterNoDrop;
 
 impl WriterNoDrop {
@@ -236,72 +528,190 @@ impl WriterNoDrop {
 impl fmt::Write for WriterNoDrop {
     /// Write stuff to serial out.
     fn write_str(&mut self, s: &str) -> fmt::Result {
-        unsafe {
-            arch::puts(s);
+        if !lock::silenced() {
+            sink::write(s.as_bytes());
         }
         Ok(())
     }
 }
 
+/// Install klogger with the filter `args` and write to `output_indicator`
+/// (see `KLoggerBuilder::output`).
+///
+/// Directives that don't fit in the filter are ignored with a warning,
+/// panics if `output_indicator` is out of range. Use `KLoggerBuilder` for
+/// more options and proper error handling.
 pub fn init(args: &str, output_indicator: u64) -> Result<(), SetLoggerError> {
-    arch::set_output(output_indicator.try_into().unwrap());
+    match KLoggerBuilder::new()
+        .filter(args)
+        .lenient()
+        .output(output_indicator)
+        .install()
+    {
+        Ok(()) => Ok(()),
+        Err(Error::SetLogger(e)) => Err(e),
+        Err(e) => panic!("klogger::init: {}", e),
+    }
+}
 
-    unsafe {
-        LOGGER.has_tsc = arch::has_tsc();
-        LOGGER.has_invariant_tsc = arch::has_invariant_tsc();
+/// Replace the filter of a running klogger with `spec` (e.g., "net=trace").
+///
+/// Safe to call while other cores are logging, they either see the old or
+/// the new filter. Must not be called while formatting a log record (e.g.,
+/// from a `Display` impl), it would wait for that record forever.
+pub fn set_filter(spec: &str) -> Result<(), Error> {
+    let mut filter = Directives::new();
+    let spec = parse_args(&mut filter, spec)?;
+
+    lock::without_interrupts(|| {
+        let mut config = LOGGER.config.write();
+        config.filter = filter;
+        config.pattern = spec.pattern;
+        if let Some(color) = spec.color {
+            config.color = color;
+        }
+        log::set_max_level(config.max_level());
+    });
+    Ok(())
+}
 
-        if LOGGER.has_tsc {
-            LOGGER.tsc_start = arch::get_timestamp();
+/// Add the directives in `spec` to the filter of a running klogger.
+///
+/// A message pattern or colour mode in `spec` replaces the current one. The
+/// filter is left unchanged if the new directives don't fit.
+pub fn add_filter(spec: &str) -> Result<(), Error> {
+    let mut added = Directives::new();
+    let spec = parse_args(&mut added, spec)?;
+
+    lock::without_interrupts(|| {
+        let mut config = LOGGER.config.write();
+        // Directives that fit may change existing ones, so we work on a copy
+        let mut filter = config.filter.clone();
+        for directive in added {
+            push_directive(&mut filter, directive)?;
+        }
+        normalize(&mut filter);
+        config.filter = filter;
+        if spec.pattern.is_some() {
+            config.pattern = spec.pattern;
+        }
+        if let Some(color) = spec.color {
+            config.color = color;
         }
+        log::set_max_level(config.max_level());
+        Ok(())
+    })
+}
 
-        let tsc_frequency_hz: Option<u64> = arch::get_tsc_frequency_hz();
+/// Change the layout of log lines of a running klogger (see `Format`).
+pub fn set_format(template: &str) -> Result<(), Error> {
+    let format = Format::parse(template)?;
+    lock::without_interrupts(|| LOGGER.config.write().format = Some(format));
+    Ok(())
+}
 
-        // Check if we run in a VM and the hypervisor can give us the TSC frequency
-        let vmm_tsc_frequency_hz: Option<u64> = arch::get_vmm_tsc_frequency_hz();
+/// Write records of a running klogger as JSON lines (see `Format::json`).
+pub fn set_json_format() {
+    lock::without_interrupts(|| LOGGER.config.write().format = Some(Format::json()));
+}
 
-        if tsc_frequency_hz.is_some() {
-            LOGGER.tsc_frequency = tsc_frequency_hz;
-        } else if vmm_tsc_frequency_hz.is_some() {
-            LOGGER.tsc_frequency = vmm_tsc_frequency_hz;
-        }
+/// Write records of a running klogger as binary frames (see
+/// `Format::binary`).
+#[cfg(feature = "binary")]
+pub fn set_binary_format() {
+    lock::without_interrupts(|| LOGGER.config.write().format = Some(Format::binary()));
+}
 
-        // Another way that segfaults in KVM:
-        // The scalable bus frequency is encoded in the bit field MSR_PLATFORM_INFO[15:8]
-        // and the nominal TSC frequency can be determined by multiplying this number
-        // by a bus speed of 100 MHz.
-        //(&mut LOGGER).tsc_frequency =
-        //    ((x86::msr::rdmsr(x86::msr::MSR_PLATFORM_INFO) >> 8) & 0xff) * 1000000;
+/// Change how much of the source file paths a running klogger prints.
+pub fn set_path_trim(paths: PathTrim) {
+    lock::without_interrupts(|| LOGGER.config.write().paths = paths);
+}
 
-        parse_args(&mut LOGGER.filter, args);
-        log::set_logger(&LOGGER).map(|()| log::set_max_level(LOGGER.filter()))
+/// The function given to `set_cpu_id`, null for `arch::cpu_id`.
+///
+/// Not part of `Config`, locking the output line needs it and must not wait
+/// for the config.
+static CPU_ID: AtomicPtr<()> = AtomicPtr::new(ptr::null_mut());
+
+/// Use `cpu_id` to figure out the core that logs a record (for `{cpu}` and
+/// to detect nested logging).
+///
+/// The default is the APIC id on x86, the MPIDR affinity on aarch64 and a
+/// number per thread on unix (and in user space with `use_ioports`). Ids
+/// have to be unique among the contexts that can log at the same time,
+/// `u32::MAX` means unknown (nested logging isn't detected then).
+pub fn set_cpu_id(cpu_id: fn() -> u32) {
+    CPU_ID.store(cpu_id as *mut (), Ordering::Relaxed);
+}
+
+/// The core we run on.
+fn cpu_id() -> u32 {
+    let cpu_id = CPU_ID.load(Ordering::Relaxed);
+    if cpu_id.is_null() {
+        arch::cpu_id()
+    } else {
+        // Only `set_cpu_id` stores (non-null) pointers
+        unsafe { mem::transmute::<*mut (), fn() -> u32>(cpu_id)() }
     }
 }
 
+/// Change the colours of a running klogger.
+pub fn set_theme(theme: Theme) {
+    lock::without_interrupts(|| LOGGER.config.write().theme = theme);
+}
+
+/// Tell klogger where the HPET registers are mapped (x86 only).
+///
+/// If the TSC frequency isn't reported by CPUID or the hypervisor, `install`
+/// measures it against the HPET (if set), or the PIT otherwise. Has to be
+/// called before `install` (or use `KLoggerBuilder::hpet_base`).
+pub fn set_hpet_base(base: u64) {
+    arch::set_hpet_base(base);
+}
+
 pub fn putchar(c: char) {
-    unsafe {
-        arch::putc(c);
-    }
+    let mut buf = [0; 4];
+    sink::write(c.encode_utf8(&mut buf).as_bytes());
 }
 
 /// Most of the filtering code is inspired or copied from
 /// https://github.com/sebasmagri/env_logger/blob/master/src/filter/mod.rs
 ///
-/// Parse a logging specification string (e.g: "crate1,crate2::mod3,crate3::x=error")
-/// and return a vector with log directives.
-fn parse_args(filter: &mut Vec<Directive, 8>, spec: &str) {
+/// Parse a logging specification string (e.g: "crate1,crate2::mod3,crate3::x=error/foo")
+/// into `filter` and return the rest of the spec (message pattern and colour
+/// mode).
+///
+/// `color=always|never|auto` sets the colour mode instead of the level of a
+/// module called `color` (none of these are valid levels).
+///
+/// Invalid directives are ignored (with a warning), but it's an error if
+/// the directives don't fit in `filter`.
+fn parse_args(filter: &mut Directives, spec: &str) -> Result<Spec, Error> {
+    parse_args_with(filter, spec, true)
+}
+
+/// `parse_args`, but unless `strict` is set, directives (or a message
+/// pattern) that don't fit are ignored with a warning as well.
+fn parse_args_with(filter: &mut Directives, spec: &str, strict: bool) -> Result<Spec, Error> {
     let mut parts = spec.split('/');
     let mods = parts.next();
+    let pattern = parts.next();
     if parts.next().is_some() {
         sprintln!(
             "warning: invalid logging spec '{}', \
              ignoring it (too many '/'s)",
             spec
         );
-        return;
+        return Ok(Spec::default());
     }
-    mods.map(|m| {
+    let mut color = None;
+    if let Some(m) = mods {
         for s in m.split(',') {
-            if s.len() == 0 {
+            if s.is_empty() {
+                continue;
+            }
+            if let Some(mode) = s.strip_prefix("color=").and_then(|m| m.trim().parse().ok()) {
+                color = Some(mode);
                 continue;
             }
             let mut parts = s.split('=');
@@ -337,28 +747,66 @@ fn parse_args(filter: &mut Vec<Directive, 8>, spec: &str) {
                     }
                 };
 
-            match filter.push(Directive {
-                name: match name {
-                    None => None,
-                    Some(name) => Some(String::from(name)),
-                },
-                level: log_level,
-            }) {
-                Ok(_) => {}
-                Err(e) => {
-                    sprintln!("Unable to add new filter {:?}", e);
+            match Directive::new(name, log_level).and_then(|d| push_directive(filter, d)) {
+                Ok(()) => {}
+                Err(e) if !strict => {
+                    sprintln!("warning: ignoring logging spec '{}' ({})", s, e);
                 }
+                Err(e) => return Err(e),
             }
         }
-    });
+    }
+    normalize(filter);
+    let pattern = match pattern.map(Pattern::new).transpose() {
+        Err(e) if !strict => {
+            sprintln!("warning: ignoring logging spec pattern ({})", e);
+            None
+        }
+        pattern => pattern?,
+    };
+    Ok(Spec { pattern, color })
+}
+
+/// Bring the directives in the order `enabled` expects.
+///
+/// A directive overrides earlier ones for the same module, the remaining
+/// ones are sorted by name length so the most specific one comes last.
+fn normalize(directives: &mut Directives) {
+    let mut i = 0;
+    while i < directives.len() {
+        if directives[i + 1..]
+            .iter()
+            .any(|d| d.name == directives[i].name)
+        {
+            directives.remove(i);
+        } else {
+            i += 1;
+        }
+    }
+    // Names are unique now, so it doesn't matter that the sort isn't stable
+    // (we still sort by name to keep the order deterministic)
+    fn key(d: &Directive) -> Option<(usize, &str)> {
+        d.name.as_ref().map(|name| (name.len(), &**name))
+    }
+    directives.sort_unstable_by(|a, b| key(a).cmp(&key(b)));
+}
+
+/// Does a directive for module `name` apply to `target`?
+///
+/// That's the case if `target` is the module itself or one of its
+/// submodules, so "crate2" matches "crate2::mod" but not "crate22".
+fn matches(name: &str, target: &str) -> bool {
+    target.starts_with(name)
+        && (target.len() == name.len() || target[name.len()..].starts_with("::"))
 }
 
 // Check whether a level and target are enabled by the set of directives.
 fn enabled(directives: &[Directive], level: Level, target: &str) -> bool {
-    // Search for the longest match, the vector is assumed to be pre-sorted.
+    // Search for the longest match, the vector is assumed to be pre-sorted
+    // (see `normalize`).
     for directive in directives.iter().rev() {
         match directive.name {
-            Some(ref name) if !target.starts_with(&**name) => {}
+            Some(ref name) if !matches(name, target) => {}
             Some(..) | None => return level <= directive.level,
         }
     }
@@ -367,46 +815,46 @@ fn enabled(directives: &[Directive], level: Level, target: &str) -> bool {
 
 #[cfg(test)]
 mod test {
-    use heapless::String;
-    use heapless::Vec as VEC;
     use log::{Level, LevelFilter};
 
-    use super::{enabled, parse_args, Directive};
+    use super::DirectiveName as String;
+    use super::{enabled, parse_args, Directive, Directives};
+    use proptest::prelude::*;
 
     #[test]
     fn filter_info() {
-        let mut filter: Vec<Directive> = Vec::new();
-        filter.push(Directive {
+        let filter = vec![Directive {
             name: None,
             level: LevelFilter::Info,
-        });
+        }];
         assert!(enabled(&filter, Level::Info, "crate1"));
         assert!(!enabled(&filter, Level::Debug, "crate1"));
     }
 
     #[test]
     fn filter_beginning_longest_match() {
-        let mut filter: Vec<Directive> = Vec::new();
-        filter.push(Directive {
-            name: Some(String::from("crate2")),
-            level: LevelFilter::Info,
-        });
-        filter.push(Directive {
-            name: Some(String::from("crate2::mod")),
-            level: LevelFilter::Debug,
-        });
-        filter.push(Directive {
-            name: Some(String::from("crate1::mod1")),
-            level: LevelFilter::Warn,
-        });
-        assert!(enabled(&filter, Level::Debug, "crate2::mod1"));
+        let filter = vec![
+            Directive {
+                name: Some(String::from("crate2")),
+                level: LevelFilter::Info,
+            },
+            Directive {
+                name: Some(String::from("crate2::mod")),
+                level: LevelFilter::Debug,
+            },
+            Directive {
+                name: Some(String::from("crate1::mod1")),
+                level: LevelFilter::Warn,
+            },
+        ];
+        assert!(enabled(&filter, Level::Debug, "crate2::mod::mod1"));
         assert!(!enabled(&filter, Level::Debug, "crate2"));
     }
 
     #[test]
     fn parse_default() {
-        let mut filter: VEC<Directive, 8> = VEC::new();
-        parse_args(&mut filter, "info,crate1::mod1=warn");
+        let mut filter: Directives = Directives::new();
+        parse_args(&mut filter, "info,crate1::mod1=warn").unwrap();
         assert!(enabled(&filter, Level::Warn, "crate1::mod1"));
         assert!(enabled(&filter, Level::Info, "crate2::mod2"));
     }
@@ -475,7 +923,7 @@ mod test {
                 level: LevelFilter::Warn,
             },
         ];
-        assert!(enabled(&logger, Level::Debug, "crate2::mod1"));
+        assert!(enabled(&logger, Level::Debug, "crate2::mod::mod1"));
         assert!(!enabled(&logger, Level::Debug, "crate2"));
     }
 
@@ -513,25 +961,26 @@ mod test {
 
     #[test]
     fn parse_args_valid() {
-        let mut dirs: VEC<Directive, 8> = VEC::new();
-        parse_args(&mut dirs, "crate1::mod1=error,crate1::mod2,crate2=debug");
+        let mut dirs: Directives = Directives::new();
+        parse_args(&mut dirs, "crate1::mod1=error,crate1::mod2,crate2=debug").unwrap();
 
+        // Sorted by specificity
         assert_eq!(dirs.len(), 3);
-        assert_eq!(dirs[0].name, Some(String::from("crate1::mod1")));
-        assert_eq!(dirs[0].level, LevelFilter::Error);
+        assert_eq!(dirs[0].name, Some(String::from("crate2")));
+        assert_eq!(dirs[0].level, LevelFilter::Debug);
 
-        assert_eq!(dirs[1].name, Some(String::from("crate1::mod2")));
-        assert_eq!(dirs[1].level, LevelFilter::max());
+        assert_eq!(dirs[1].name, Some(String::from("crate1::mod1")));
+        assert_eq!(dirs[1].level, LevelFilter::Error);
 
-        assert_eq!(dirs[2].name, Some(String::from("crate2")));
-        assert_eq!(dirs[2].level, LevelFilter::Debug);
+        assert_eq!(dirs[2].name, Some(String::from("crate1::mod2")));
+        assert_eq!(dirs[2].level, LevelFilter::max());
     }
 
     #[test]
     fn parse_spec_invalid_crate() {
         // test parse_spec with multiple = in specification
-        let mut dirs: VEC<Directive, 8> = VEC::new();
-        parse_args(&mut dirs, "crate1::mod1=warn=info,crate2=debug");
+        let mut dirs: Directives = Directives::new();
+        parse_args(&mut dirs, "crate1::mod1=warn=info,crate2=debug").unwrap();
 
         assert_eq!(dirs.len(), 1);
         assert_eq!(dirs[0].name, Some(String::from("crate2")));
@@ -541,8 +990,8 @@ mod test {
     #[test]
     fn parse_spec_invalid_level() {
         // test parse_spec with 'noNumber' as log level
-        let mut dirs: VEC<Directive, 8> = VEC::new();
-        parse_args(&mut dirs, "crate1::mod1=noNumber,crate2=debug");
+        let mut dirs: Directives = Directives::new();
+        parse_args(&mut dirs, "crate1::mod1=noNumber,crate2=debug").unwrap();
 
         assert_eq!(dirs.len(), 1);
         assert_eq!(dirs[0].name, Some(String::from("crate2")));
@@ -552,8 +1001,8 @@ mod test {
     #[test]
     fn parse_spec_string_level() {
         // test parse_spec with 'warn' as log level
-        let mut dirs: VEC<Directive, 8> = VEC::new();
-        parse_args(&mut dirs, "crate1::mod1=wrong,crate2=warn");
+        let mut dirs: Directives = Directives::new();
+        parse_args(&mut dirs, "crate1::mod1=wrong,crate2=warn").unwrap();
 
         assert_eq!(dirs.len(), 1);
         assert_eq!(dirs[0].name, Some(String::from("crate2")));
@@ -563,8 +1012,8 @@ mod test {
     #[test]
     fn parse_spec_empty_level() {
         // test parse_spec with '' as log level\
-        let mut dirs: VEC<Directive, 8> = VEC::new();
-        parse_args(&mut dirs, "crate1::mod1=wrong,crate2=");
+        let mut dirs: Directives = Directives::new();
+        parse_args(&mut dirs, "crate1::mod1=wrong,crate2=").unwrap();
 
         assert_eq!(dirs.len(), 1);
         assert_eq!(dirs[0].name, Some(String::from("crate2")));
@@ -574,8 +1023,8 @@ mod test {
     #[test]
     fn parse_spec_global() {
         // test parse_spec with no crate
-        let mut dirs: VEC<Directive, 8> = VEC::new();
-        parse_args(&mut dirs, "warn,crate2=debug");
+        let mut dirs: Directives = Directives::new();
+        parse_args(&mut dirs, "warn,crate2=debug").unwrap();
 
         assert_eq!(dirs.len(), 2);
         assert_eq!(dirs[0].name, None);
@@ -583,4 +1032,284 @@ mod test {
         assert_eq!(dirs[1].name, Some(String::from("crate2")));
         assert_eq!(dirs[1].level, LevelFilter::Debug);
     }
+
+    #[test]
+    fn parse_spec_pattern() {
+        let mut dirs: Directives = Directives::new();
+        let spec = parse_args(&mut dirs, "crate1=debug,info/irq.*enabled").unwrap();
+
+        assert_eq!(dirs.len(), 2);
+        let pattern = spec.pattern.expect("pattern");
+        assert!(pattern.is_match("MSI-X irq 3 enabled"));
+        assert!(!pattern.is_match("MSI-X irq 3 disabled"));
+
+        let mut dirs: Directives = Directives::new();
+        assert!(parse_args(&mut dirs, "info").unwrap().pattern.is_none());
+    }
+
+    #[test]
+    fn parse_spec_too_many_slashes() {
+        let mut dirs: Directives = Directives::new();
+        assert!(parse_args(&mut dirs, "info/a/b").unwrap().pattern.is_none());
+        assert_eq!(dirs.len(), 0);
+    }
+
+    #[test]
+    fn match_module_boundary() {
+        let logger = vec![
+            Directive {
+                name: Some(String::from("crate2")),
+                level: LevelFilter::Info,
+            },
+            Directive {
+                name: Some(String::from("crate2::mod")),
+                level: LevelFilter::Debug,
+            },
+        ];
+        assert!(!enabled(&logger, Level::Info, "crate22"));
+        assert!(!enabled(&logger, Level::Debug, "crate2::mod1"));
+        assert!(enabled(&logger, Level::Info, "crate2::mod1"));
+        assert!(enabled(&logger, Level::Debug, "crate2::mod"));
+    }
+
+    #[test]
+    fn parse_spec_sorts_by_specificity() {
+        let mut dirs: Directives = Directives::new();
+        parse_args(&mut dirs, "crate2::mod=debug,crate2=info,warn").unwrap();
+
+        assert_eq!(dirs.len(), 3);
+        assert_eq!(dirs[0].name, None);
+        assert_eq!(dirs[1].name, Some(String::from("crate2")));
+        assert_eq!(dirs[2].name, Some(String::from("crate2::mod")));
+        assert!(enabled(&dirs, Level::Debug, "crate2::mod"));
+        assert!(!enabled(&dirs, Level::Debug, "crate2::other"));
+    }
+
+    #[test]
+    fn parse_spec_later_overrides_earlier() {
+        let mut dirs: Directives = Directives::new();
+        parse_args(&mut dirs, "crate1=trace,info,crate1=error,warn").unwrap();
+
+        assert_eq!(dirs.len(), 2);
+        assert_eq!(dirs[0].name, None);
+        assert_eq!(dirs[0].level, LevelFilter::Warn);
+        assert_eq!(dirs[1].name, Some(String::from("crate1")));
+        assert_eq!(dirs[1].level, LevelFilter::Error);
+    }
+
+    #[test]
+    fn parse_spec_color() {
+        use super::ColorMode;
+
+        let mut dirs: Directives = Directives::new();
+        let spec = parse_args(&mut dirs, "info,color=never,crate1=debug").unwrap();
+        assert_eq!(spec.color, Some(ColorMode::Never));
+        assert_eq!(dirs.len(), 2);
+
+        // Not a colour mode, so it's the level of a module called `color`
+        let mut dirs: Directives = Directives::new();
+        let spec = parse_args(&mut dirs, "color=debug,color=Auto").unwrap();
+        assert_eq!(spec.color, Some(ColorMode::Auto));
+        assert_eq!(dirs.len(), 1);
+        assert_eq!(dirs[0].name, Some(String::from("color")));
+        assert_eq!(dirs[0].level, LevelFilter::Debug);
+    }
+
+    /// Does `name` cover `target` with the `::` boundary rule?
+    fn reference_matches(name: &str, target: &str) -> bool {
+        target == name || target.starts_with(&std::format!("{}::", name))
+    }
+
+    /// What env_logger does (with module boundaries): The last directive
+    /// for the most specific matching module wins.
+    fn reference_enabled(
+        dirs: &[(Option<String>, LevelFilter)],
+        level: Level,
+        target: &str,
+    ) -> bool {
+        let mut best: Option<(usize, LevelFilter)> = None;
+        for (name, dir_level) in dirs {
+            let specificity = match name {
+                None => 0,
+                Some(name) if reference_matches(name, target) => name.len(),
+                Some(_) => continue,
+            };
+            match best {
+                Some((s, _)) if specificity < s => {}
+                _ => best = Some((specificity, *dir_level)),
+            }
+        }
+        best.is_some_and(|(_, dir_level)| level <= dir_level)
+    }
+
+    fn module_path() -> impl Strategy<Value = std::string::String> {
+        prop::collection::vec(prop::sample::select(vec!["a", "ab", "b", "a_b"]), 1..4)
+            .prop_map(|segments| segments.join("::"))
+    }
+
+    fn level_filter() -> impl Strategy<Value = LevelFilter> {
+        prop::sample::select(vec![
+            LevelFilter::Off,
+            LevelFilter::Error,
+            LevelFilter::Warn,
+            LevelFilter::Info,
+            LevelFilter::Debug,
+            LevelFilter::Trace,
+        ])
+    }
+
+    proptest! {
+        #[test]
+        fn enabled_like_env_logger(
+            dirs in prop::collection::vec((prop::option::of(module_path()), level_filter()), 0..8),
+            target in module_path(),
+        ) {
+            let spec: std::vec::Vec<std::string::String> = dirs
+                .iter()
+                .map(|(name, level)| match name {
+                    Some(name) => std::format!("{}={}", name, level),
+                    None => std::format!("{}", level),
+                })
+                .collect();
+            let mut parsed: Directives = Directives::new();
+            parse_args(&mut parsed, &spec.join(",")).unwrap();
+
+            let reference: std::vec::Vec<(Option<String>, LevelFilter)> = dirs
+                .iter()
+                .map(|(name, level)| (name.as_ref().map(|n| String::from(n.as_str())), *level))
+                .collect();
+            for level in [Level::Error, Level::Warn, Level::Info, Level::Debug, Level::Trace] {
+                prop_assert_eq!(
+                    enabled(&parsed, level, &target),
+                    reference_enabled(&reference, level, &target),
+                    "spec {:?}, target {}, level {}", spec, target, level
+                );
+            }
+        }
+    }
+
+    #[test]
+    #[cfg(not(feature = "alloc"))]
+    fn parse_spec_too_many_directives() {
+        use super::{Error, MAX_DIRECTIVES};
+
+        let mut dirs: Directives = Directives::new();
+        for i in 0..MAX_DIRECTIVES {
+            parse_args(&mut dirs, &std::format!("crate{}=info", i)).unwrap();
+        }
+        // Overriding a module we have already fits
+        parse_args(&mut dirs, "crate2=debug").unwrap();
+        assert_eq!(dirs.len(), MAX_DIRECTIVES);
+        assert!(dirs
+            .iter()
+            .any(|d| d.name.as_deref() == Some("crate2") && d.level == LevelFilter::Debug));
+
+        assert!(matches!(
+            parse_args(&mut dirs, &std::format!("crate{}=debug", MAX_DIRECTIVES)),
+            Err(Error::TooManyDirectives)
+        ));
+        assert_eq!(dirs.len(), MAX_DIRECTIVES);
+    }
+
+    #[test]
+    #[cfg(not(feature = "alloc"))]
+    fn parse_spec_lenient() {
+        use super::{parse_args_with, MAX_DIRECTIVES, MAX_DIRECTIVE_NAME};
+
+        // One directive too many and a name that is too long
+        let mut spec = std::string::String::new();
+        for i in 0..MAX_DIRECTIVES + 1 {
+            spec += &std::format!("crate{}=info,", i);
+        }
+        spec += &"m".repeat(MAX_DIRECTIVE_NAME + 1);
+        let mut dirs: Directives = Directives::new();
+        parse_args_with(&mut dirs, &spec, false).unwrap();
+        assert_eq!(dirs.len(), MAX_DIRECTIVES);
+        assert!(dirs.iter().all(|d| d.name.as_ref().unwrap().len() == 6));
+    }
+
+    #[test]
+    #[cfg(not(feature = "alloc"))]
+    fn parse_spec_name_too_long() {
+        use super::{Error, MAX_DIRECTIVE_NAME};
+
+        let mut spec: String = String::new();
+        for _ in 0..MAX_DIRECTIVE_NAME / 4 {
+            spec.push_str("m::n").unwrap();
+        }
+        let mut dirs: Directives = Directives::new();
+        parse_args(&mut dirs, &spec).unwrap();
+        assert_eq!(dirs[0].name.as_ref(), Some(&spec));
+
+        let mut dirs: Directives = Directives::new();
+        let spec = std::format!("{}x=info", spec);
+        assert!(matches!(
+            parse_args(&mut dirs, &spec),
+            Err(Error::DirectiveNameTooLong)
+        ));
+    }
+
+    #[test]
+    #[cfg(all(not(feature = "use_ioports"), target_family = "unix"))]
+    fn unix_timestamps_are_monotonic_ns() {
+        use super::arch;
+
+        assert!(arch::has_tsc() && arch::has_invariant_tsc());
+        assert_eq!(arch::get_tsc_frequency_hz(), Some(1_000_000_000));
+
+        let start = arch::get_timestamp();
+        std::thread::sleep(std::time::Duration::from_millis(2));
+        let end = arch::get_timestamp();
+        assert!(end - start >= 2_000_000);
+    }
+
+    #[test]
+    #[cfg(all(not(feature = "use_ioports"), target_family = "unix"))]
+    fn unix_honours_no_color() {
+        use super::arch::color_allowed;
+
+        assert!(color_allowed(true, None));
+        assert!(color_allowed(true, Some("".into())));
+        assert!(!color_allowed(true, Some("1".into())));
+        assert!(!color_allowed(false, None));
+    }
+
+    #[test]
+    #[cfg(all(not(feature = "use_ioports"), target_family = "unix"))]
+    fn unix_cpu_id_per_thread() {
+        use super::arch::cpu_id;
+
+        let id = cpu_id();
+        assert_eq!(cpu_id(), id);
+        let other = std::thread::spawn(cpu_id).join().unwrap();
+        assert_ne!(other, id);
+    }
+
+    #[test]
+    fn runtime_filter_changes() {
+        use super::{add_filter, set_filter, LOGGER};
+        use log::{Log, Metadata};
+        use sink::test::REGISTRY;
+
+        // `KLoggerBuilder::install` tests look at the filter too
+        let _registry = REGISTRY.lock();
+
+        let enabled = |level, target| {
+            LOGGER.enabled(&Metadata::builder().level(level).target(target).build())
+        };
+
+        set_filter("warn").unwrap();
+        assert!(enabled(Level::Warn, "net"));
+        assert!(!enabled(Level::Info, "net"));
+        assert_eq!(log::max_level(), LevelFilter::Warn);
+
+        add_filter("net=trace").unwrap();
+        assert!(enabled(Level::Trace, "net::tcp"));
+        assert!(!enabled(Level::Info, "fs"));
+        assert_eq!(log::max_level(), LevelFilter::Trace);
+
+        set_filter("error").unwrap();
+        assert!(!enabled(Level::Trace, "net::tcp"));
+        assert_eq!(log::max_level(), LevelFilter::Error);
+    }
 }
@@ -0,0 +1,329 @@
+//! Locking of the output line.
+//!
+//! Interrupts are disabled while `SERIAL_LINE_MUTEX` is held: If an
+//! interrupt handler logs while the interrupted code holds the lock on the
+//! same core, it would spin forever.
+//!
+//! The same happens if logging a record logs again (e.g., a `Display` impl
+//! that calls `info!`). We remember which core holds the lock, nested
+//! records are dropped and counted instead, the next line reports how many.
+//!
+//! A panic handler calls `emergency` to get the line for itself, even if
+//! the lock is held by a core that won't release it anymore.
+
+use core::fmt::Write;
+use core::hint;
+use core::mem;
+use core::ops;
+use core::sync::atomic::{AtomicBool, AtomicU64, AtomicUsize, Ordering};
+
+use super::{arch, cpu_id, sink, WriterNoDrop, SERIAL_LINE_MUTEX};
+
+/// How often `emergency` tries to take the lock before breaking it.
+const EMERGENCY_SPINS: usize = 1 << 24;
+
+/// `OWNER` if nobody holds the lock (CPU ids are only 32 bits).
+const NO_OWNER: u64 = u64::MAX;
+
+/// CPU id of a context that can't be told apart from others (e.g., a unix
+/// thread that is being torn down), we can't detect nested logging there.
+pub(crate) const UNKNOWN_CPU: u32 = u32::MAX;
+
+/// The core holding `SERIAL_LINE_MUTEX` (if we took it).
+static OWNER: AtomicU64 = AtomicU64::new(NO_OWNER);
+
+/// Nested records that have not been reported yet.
+static DROPPED: AtomicUsize = AtomicUsize::new(0);
+
+/// All nested records dropped so far.
+static DROPPED_TOTAL: AtomicUsize = AtomicUsize::new(0);
+
+/// Set once `emergency` owns the line.
+static EMERGENCY: AtomicBool = AtomicBool::new(false);
+
+/// The core that called `emergency`.
+static EMERGENCY_OWNER: AtomicU64 = AtomicU64::new(NO_OWNER);
+
+/// Holds `SERIAL_LINE_MUTEX` with interrupts disabled.
+pub(crate) struct LineGuard<'a> {
+    /// `None` in emergency mode (or once dropped).
+    lock: Option<spin::MutexGuard<'a, bool>>,
+    /// Interrupt state before we took the lock.
+    interrupts: arch::InterruptState,
+    /// The core we run on.
+    cpu: u32,
+}
+
+/// Disable interrupts and take `SERIAL_LINE_MUTEX`.
+///
+/// Returns `None` (and counts a dropped record) if this core holds the lock
+/// already (unless its id is `UNKNOWN_CPU`, then it waits). In emergency mode, the core that called `emergency` doesn't need
+/// the lock and other cores get `None`.
+pub(crate) fn line<'a>() -> Option<LineGuard<'a>> {
+    // Interrupts go off first, or one could come in between taking the lock
+    // and disabling them
+    let interrupts = arch::disable_interrupts();
+    let id = cpu_id();
+    let cpu = id as u64;
+    if EMERGENCY.load(Ordering::Acquire) {
+        if EMERGENCY_OWNER.load(Ordering::Relaxed) == cpu {
+            return Some(LineGuard {
+                lock: None,
+                interrupts,
+                cpu: id,
+            });
+        }
+        arch::restore_interrupts(interrupts);
+        return None;
+    }
+    // Only we can store our id, so nobody changes it under our feet if it's
+    // already there
+    if id != UNKNOWN_CPU && OWNER.load(Ordering::Relaxed) == cpu {
+        DROPPED.fetch_add(1, Ordering::Relaxed);
+        DROPPED_TOTAL.fetch_add(1, Ordering::Relaxed);
+        arch::restore_interrupts(interrupts);
+        return None;
+    }
+
+    let lock = SERIAL_LINE_MUTEX.lock();
+    OWNER.store(cpu, Ordering::Relaxed);
+    let dropped = DROPPED.swap(0, Ordering::Relaxed);
+    if dropped > 0 {
+        let _ = write!(
+            WriterNoDrop,
+            "[klogger] dropped {} nested record(s)\r\n",
+            dropped
+        );
+    }
+    Some(LineGuard {
+        lock: Some(lock),
+        interrupts,
+        cpu: id,
+    })
+}
+
+impl<'a> LineGuard<'a> {
+    /// The core holding the line (saves looking it up again).
+    pub(crate) fn cpu(&self) -> u32 {
+        self.cpu
+    }
+}
+
+/// Run `f` with interrupts disabled.
+///
+/// For changes to state that `log` reads (the config, the sinks): an
+/// interrupt handler that logs while we hold the write lock would wait for
+/// it forever.
+pub(crate) fn without_interrupts<F: FnOnce() -> R, R>(f: F) -> R {
+    let interrupts = arch::disable_interrupts();
+    let result = f();
+    arch::restore_interrupts(interrupts);
+    result
+}
+
+/// Is output of this core dropped because another core is in emergency
+/// mode?
+pub(crate) fn silenced() -> bool {
+    EMERGENCY.load(Ordering::Acquire) && EMERGENCY_OWNER.load(Ordering::Relaxed) != cpu_id() as u64
+}
+
+/// Take over the output line for good, meant for panic handlers.
+///
+/// Disables interrupts and waits (for a while) until the core printing
+/// right now is done. If it doesn't finish (e.g., it died or is this core),
+/// the lock is broken and a "lock broken" marker printed. Afterwards all
+/// output of this core goes out without locking and output of other cores
+/// is dropped. Sinks that buffer output are flushed, that doesn't print
+/// the contents of a `RingBuffer` (`panic::handle` takes a `dump` hook for
+/// that, or call `RingBuffer::dump`).
+///
+/// Only the first call does anything (if two cores panic, the second one
+/// stays silent).
+pub fn emergency() {
+    // They stay off
+    let _ = arch::disable_interrupts();
+    let cpu = cpu_id() as u64;
+    if EMERGENCY_OWNER
+        .compare_exchange(NO_OWNER, cpu, Ordering::Relaxed, Ordering::Relaxed)
+        .is_err()
+    {
+        return;
+    }
+
+    let mut broken = OWNER.load(Ordering::Relaxed) == cpu;
+    if !broken {
+        let mut spins = 0;
+        loop {
+            if let Some(lock) = SERIAL_LINE_MUTEX.try_lock() {
+                // Keep it, cores waiting for it stay out of the way
+                mem::forget(lock);
+                break;
+            }
+            spins += 1;
+            if spins == EMERGENCY_SPINS {
+                broken = true;
+                break;
+            }
+            hint::spin_loop();
+        }
+    }
+    EMERGENCY.store(true, Ordering::Release);
+
+    if broken {
+        // Whoever held the lock might be in the middle of a line
+        let _ = WriterNoDrop.write_str("\r\n[klogger] lock broken\r\n");
+    }
+    let dropped = DROPPED.swap(0, Ordering::Relaxed);
+    if dropped > 0 {
+        let _ = write!(
+            WriterNoDrop,
+            "[klogger] dropped {} nested record(s)\r\n",
+            dropped
+        );
+    }
+    sink::flush();
+}
+
+/// Are we in emergency mode?
+pub fn in_emergency() -> bool {
+    EMERGENCY.load(Ordering::Acquire)
+}
+
+/// Back to normal (tests can't just stop like a panicked kernel).
+#[cfg(all(test, not(feature = "use_ioports"), target_family = "unix"))]
+pub(crate) fn leave_emergency() {
+    EMERGENCY.store(false, Ordering::Release);
+    EMERGENCY_OWNER.store(NO_OWNER, Ordering::Relaxed);
+    OWNER.store(NO_OWNER, Ordering::Relaxed);
+    unsafe { SERIAL_LINE_MUTEX.force_unlock() };
+}
+
+/// How many records were dropped because they were logged while logging on
+/// the same core.
+pub fn dropped_nested() -> usize {
+    DROPPED_TOTAL.load(Ordering::Relaxed)
+}
+
+impl<'a> ops::Drop for LineGuard<'a> {
+    /// Release the lock, then restore interrupts.
+    fn drop(&mut self) {
+        if let Some(lock) = self.lock.take() {
+            OWNER.store(NO_OWNER, Ordering::Relaxed);
+            drop(lock);
+        }
+        arch::restore_interrupts(self.interrupts);
+    }
+}
+
+#[cfg(all(test, not(feature = "use_ioports"), target_family = "unix"))]
+mod test {
+    use core::fmt;
+    use core::fmt::Write;
+
+    use super::{
+        dropped_nested, emergency, in_emergency, leave_emergency, line, without_interrupts,
+    };
+    use arch::{disable_interrupts, interrupts_enabled, restore_interrupts};
+    use sink::test::{Memory, REGISTRY};
+    use sink::{add_sink, clear_sinks};
+    use {Writer, SERIAL_LINE_MUTEX};
+
+    #[test]
+    fn interrupts_off_while_locked() {
+        let _registry = REGISTRY.lock();
+        assert!(interrupts_enabled());
+        {
+            let _line = line().unwrap();
+            assert!(!interrupts_enabled());
+            assert!(SERIAL_LINE_MUTEX.try_lock().is_none());
+        }
+        assert!(interrupts_enabled());
+    }
+
+    #[test]
+    fn interrupts_stay_off() {
+        // Locking with interrupts disabled (e.g., in an interrupt handler)
+        // must not enable them
+        let _registry = REGISTRY.lock();
+        let state = disable_interrupts();
+        drop(line());
+        assert!(!interrupts_enabled());
+        restore_interrupts(state);
+        assert!(interrupts_enabled());
+    }
+
+    #[test]
+    fn config_changes_without_interrupts() {
+        let _registry = REGISTRY.lock();
+        assert!(without_interrupts(|| !interrupts_enabled()));
+        assert!(interrupts_enabled());
+    }
+
+    /// Logs while it's being logged.
+    struct Nested;
+
+    impl fmt::Display for Nested {
+        fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+            sprintln!("inner");
+            f.write_str("outer")
+        }
+    }
+
+    #[test]
+    fn nested_is_dropped() {
+        let _registry = REGISTRY.lock();
+        let dropped = dropped_nested();
+        {
+            let _line = line().unwrap();
+            assert!(line().is_none());
+            // Still disabled by the outer lock
+            assert!(!interrupts_enabled());
+        }
+        let _ = write!(Writer::get(), "{}", Nested);
+        assert_eq!(dropped_nested(), dropped + 2);
+        assert!(interrupts_enabled());
+        // Other threads still wait for the lock instead
+        let _line = line().unwrap();
+        assert!(
+            std::thread::spawn(|| SERIAL_LINE_MUTEX.try_lock().is_none())
+                .join()
+                .unwrap()
+        );
+    }
+
+    #[test]
+    fn emergency_breaks_lock() {
+        static MEMORY: Memory<128> = Memory::new();
+        let _registry = REGISTRY.lock();
+        add_sink(&MEMORY).expect("add sink");
+
+        // A core that died while printing
+        std::thread::spawn(|| {
+            let line = line().unwrap();
+            sprint!("half a li");
+            std::mem::forget(line);
+        })
+        .join()
+        .unwrap();
+        emergency();
+        assert!(in_emergency());
+        assert!(!interrupts_enabled());
+        sprintln!("panicked");
+        // Other cores are quiet now
+        std::thread::spawn(|| {
+            assert!(line().is_none());
+            sprint!("other core");
+        })
+        .join()
+        .unwrap();
+
+        // Before other threads can print again
+        clear_sinks();
+        leave_emergency();
+        restore_interrupts(true);
+        assert_eq!(
+            &MEMORY.0.lock()[..],
+            &b"half a li\r\n[klogger] lock broken\r\npanicked\r\n"[..]
+        );
+    }
+}
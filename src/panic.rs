@@ -0,0 +1,235 @@
+//! Helpers for panic handlers (with the `panic` feature).
+//!
+//! All output goes through `emergency` mode, so it comes out even if another
+//! core holds the output line.
+//!
+//! ```ignore
+//! #[panic_handler]
+//! fn panic(info: &core::panic::PanicInfo) -> ! {
+//!     // The stack of the panicking core, if we want a backtrace
+//!     let stack = Some(kernel_stack_bottom()..kernel_stack_top());
+//!     unsafe { klogger::panic::handle(info, stack, Some(|| klogger::ringbuf::DMESG.dump())) }
+//! }
+//! ```
+//!
+//! The backtrace follows the frame pointer chain, so it needs a kernel
+//! built with `-C force-frame-pointers=yes`. It only prints return
+//! addresses, use `addr2line` to turn them into source locations. The walk
+//! never leaves the stack it's given, so a corrupted chain can't make it
+//! read random memory.
+
+use core::fmt;
+use core::fmt::Write;
+use core::ops::Range;
+use core::panic::{Location, PanicInfo};
+
+use super::{arch, cpu_id, emergency, sink, ElapsedTime, TimestampMode, WriterNoDrop, LOGGER};
+
+/// `backtrace` stops after that many frames.
+pub const MAX_FRAMES: usize = 64;
+
+/// Write the panic line, laid out like a log record at level "PANIC".
+fn write_report<W: Write>(
+    w: &mut W,
+    elapsed: &ElapsedTime,
+    cpu: u32,
+    location: Option<&Location>,
+    message: &dyn fmt::Display,
+) -> fmt::Result {
+    write!(w, "{} [PANIC] cpu{} - ", elapsed, cpu)?;
+    if let Some(location) = location {
+        write!(
+            w,
+            "{}:{}:{}: ",
+            location.file(),
+            location.line(),
+            location.column()
+        )?;
+    }
+    write!(w, "{}\r\n", message)
+}
+
+/// Enter emergency mode and print `info` with location, message, CPU id
+/// and the time since klogger was installed.
+pub fn report(info: &PanicInfo) {
+    emergency();
+    // The config is only locked for writing briefly, unless we panicked
+    // right then
+    let timestamps = LOGGER
+        .config
+        .try_read()
+        .map_or(TimestampMode::Auto, |config| config.timestamps);
+    let elapsed = LOGGER.elapsed(timestamps);
+    let _ = write_report(
+        &mut WriterNoDrop,
+        &elapsed,
+        cpu_id(),
+        info.location(),
+        &info.message(),
+    );
+}
+
+/// Call `f` with the return addresses of the frame pointer chain starting
+/// at `fp` (at most `MAX_FRAMES`).
+///
+/// Every frame starts with the caller's frame pointer followed by the
+/// return address (true for x86_64 and aarch64). The walk stops at a null
+/// or misaligned frame pointer, a frame that isn't within `stack` and if
+/// the chain doesn't lead up the stack.
+///
+/// # Safety
+///
+/// `stack` has to be readable memory.
+unsafe fn walk<F: FnMut(usize)>(mut fp: usize, stack: Range<usize>, mut f: F) {
+    let frame_size = 2 * core::mem::size_of::<usize>();
+    for _ in 0..MAX_FRAMES {
+        if fp == 0
+            || fp < stack.start
+            || fp.checked_add(frame_size).is_none_or(|end| end > stack.end)
+            || !fp.is_multiple_of(core::mem::align_of::<usize>())
+        {
+            break;
+        }
+        let frame = fp as *const usize;
+        let (next, ret) = (*frame, *frame.add(1));
+        if ret == 0 {
+            break;
+        }
+        f(ret);
+        // The stack grows down, frames of callers are above ours
+        if next <= fp {
+            break;
+        }
+        fp = next;
+    }
+}
+
+/// Print the return addresses of the current call stack, `stack` is the
+/// address range of that stack (frames outside of it end the backtrace).
+///
+/// # Safety
+///
+/// `stack` has to be readable memory.
+#[cfg(any(target_arch = "x86_64", target_arch = "aarch64"))]
+pub unsafe fn backtrace(stack: Range<usize>) {
+    let fp: usize;
+    #[cfg(target_arch = "x86_64")]
+    core::arch::asm!("mov {}, rbp", out(reg) fp, options(nomem, nostack));
+    #[cfg(target_arch = "aarch64")]
+    core::arch::asm!("mov {}, x29", out(reg) fp, options(nomem, nostack));
+    let _ = WriterNoDrop.write_str("backtrace:\r\n");
+    let mut frame = 0;
+    walk(fp, stack, |ret| {
+        let _ = write!(WriterNoDrop, "  #{:<2} {:#018x}\r\n", frame, ret);
+        frame += 1;
+    });
+}
+
+/// Stop this core for good (`hlt` with interrupts disabled on x86, `wfi`
+/// with IRQs masked on aarch64, abort on unix).
+pub fn halt() -> ! {
+    arch::halt()
+}
+
+/// Everything a panic handler usually does: `report` the panic, print a
+/// `backtrace` of `stack` (if given and supported), call `dump` (e.g., to
+/// print the tail of a `RingBuffer`), flush the sinks and `halt`.
+///
+/// # Safety
+///
+/// `stack` has to be readable memory.
+pub unsafe fn handle(info: &PanicInfo, stack: Option<Range<usize>>, dump: Option<fn()>) -> ! {
+    report(info);
+    #[cfg(any(target_arch = "x86_64", target_arch = "aarch64"))]
+    if let Some(stack) = stack {
+        backtrace(stack);
+    }
+    #[cfg(not(any(target_arch = "x86_64", target_arch = "aarch64")))]
+    let _ = stack;
+    if let Some(dump) = dump {
+        let _ = WriterNoDrop.write_str("log tail:\r\n");
+        dump();
+    }
+    sink::flush();
+    halt()
+}
+
+#[cfg(test)]
+mod test {
+    use core::panic::Location;
+
+    use heapless::String;
+
+    use super::{walk, write_report};
+    use ElapsedTime;
+
+    #[test]
+    fn report_line() {
+        let mut out: String<128> = String::new();
+        write_report(
+            &mut out,
+            &ElapsedTime::Nanoseconds(1234),
+            2,
+            Some(Location::caller()),
+            &format_args!("boom {}", 42),
+        )
+        .unwrap();
+        assert!(out.starts_with("      1234 [PANIC] cpu2 - src/panic.rs:"));
+        assert!(out.ends_with(": boom 42\r\n"));
+
+        out.clear();
+        write_report(
+            &mut out,
+            &ElapsedTime::Undetermined,
+            0,
+            None,
+            &"no location",
+        )
+        .unwrap();
+        assert_eq!(out, " [PANIC] cpu0 - no location\r\n");
+    }
+
+    /// Walk the frames in `stack`, `[next, return address]` each, `next`
+    /// is an index into `stack` (`usize::MAX` for the null frame pointer).
+    fn walk_stack(stack: &mut [usize]) -> std::vec::Vec<usize> {
+        let range = stack.as_ptr_range();
+        let range = range.start as usize..range.end as usize;
+        let base = stack.as_ptr() as usize;
+        let word = core::mem::size_of::<usize>();
+        for next in stack.iter_mut().step_by(2) {
+            *next = match *next {
+                usize::MAX => 0,
+                index => base + index * word,
+            };
+        }
+        let mut addresses = std::vec::Vec::new();
+        unsafe { walk(base, range, |ret| addresses.push(ret)) };
+        addresses
+    }
+
+    #[test]
+    fn frame_pointer_chain() {
+        let mut stack = [2, 0x1111, 4, 0x2222, usize::MAX, 0x3333];
+        assert_eq!(walk_stack(&mut stack), [0x1111, 0x2222, 0x3333]);
+
+        // A chain that points down the stack stops
+        let mut stack = [2, 0x1111, 0, 0x2222];
+        assert_eq!(walk_stack(&mut stack), [0x1111, 0x2222]);
+
+        // So does a zero return address
+        let mut stack = [2, 0x1111, 4, 0, usize::MAX, 0x3333];
+        assert_eq!(walk_stack(&mut stack), [0x1111]);
+
+        // And a frame that doesn't fit on the stack
+        let mut stack = [2, 0x1111, 3, 0x2222];
+        assert_eq!(walk_stack(&mut stack), [0x1111, 0x2222]);
+        let mut stack = [2, 0x1111, 6, 0x2222];
+        assert_eq!(walk_stack(&mut stack), [0x1111, 0x2222]);
+
+        assert!(unsafe {
+            let mut called = false;
+            walk(0, 0..usize::MAX, |_| called = true);
+            !called
+        });
+    }
+}
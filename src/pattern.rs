@@ -0,0 +1,246 @@
+//! Message filter, the part after the `/` in a filter spec.
+//!
+//! Patterns are matched against the formatted message of a log record, the
+//! message is streamed through a `Matcher` so it can have any length. A
+//! pattern without special characters matches if it is a substring of the
+//! message, in addition we support a small regex subset:
+//!
+//! - `.` matches any byte
+//! - `*` matches zero or more of the preceding character (or `.`)
+//! - `^` at the start and `$` at the end anchor the pattern
+//! - `\` matches the next character literally (e.g., `\.`)
+
+use core::fmt;
+
+use heapless::Vec;
+
+use super::Error;
+
+/// Maximum length of a message pattern.
+pub const MAX_PATTERN: usize = 64;
+
+/// A parsed message pattern.
+#[derive(Debug, Clone)]
+pub(crate) struct Pattern {
+    /// Only match at the start of the message (`^`).
+    start: bool,
+    /// Only match at the end of the message (`$`).
+    end: bool,
+    items: Vec<Item, MAX_PATTERN>,
+}
+
+/// A single (possibly escaped) character of a pattern or `.`.
+#[derive(Debug, Clone, Copy)]
+enum Atom {
+    Any,
+    Byte(u8),
+}
+
+impl Atom {
+    fn matches(self, b: u8) -> bool {
+        match self {
+            Atom::Any => true,
+            Atom::Byte(expected) => expected == b,
+        }
+    }
+}
+
+/// An atom, optionally followed by `*`.
+#[derive(Debug, Clone, Copy)]
+struct Item {
+    atom: Atom,
+    star: bool,
+}
+
+/// Set of positions in a pattern (one bit per item, plus one for the end).
+type States = u128;
+
+impl Pattern {
+    pub(crate) fn new(pattern: &str) -> Result<Pattern, Error> {
+        if pattern.len() > MAX_PATTERN {
+            return Err(Error::PatternTooLong);
+        }
+        let mut re = pattern.as_bytes();
+        let start = match re.strip_prefix(b"^") {
+            Some(rest) => {
+                re = rest;
+                true
+            }
+            None => false,
+        };
+        let mut end = false;
+        let mut items = Vec::new();
+        while !re.is_empty() {
+            if re == b"$" {
+                end = true;
+                break;
+            }
+            let (atom, rest) = next_atom(re);
+            let (star, rest) = match rest.strip_prefix(b"*") {
+                Some(rest) => (true, rest),
+                None => (false, rest),
+            };
+            // Can't fail, there are fewer items than bytes
+            let _ = items.push(Item { atom, star });
+            re = rest;
+        }
+        Ok(Pattern { start, end, items })
+    }
+
+    /// Start matching a message, write the message to the `Matcher`.
+    pub(crate) fn matcher(&self) -> Matcher<'_> {
+        Matcher {
+            pattern: self,
+            states: self.closure(1),
+            matched: false,
+        }
+    }
+
+    /// Does the pattern match (somewhere in) `text`?
+    #[cfg(test)]
+    pub(crate) fn is_match(&self, text: &str) -> bool {
+        let mut matcher = self.matcher();
+        let _ = fmt::Write::write_str(&mut matcher, text);
+        matcher.is_match()
+    }
+
+    /// Set of positions after the last item (the pattern matched).
+    fn done(&self) -> States {
+        1 << self.items.len()
+    }
+
+    /// Add the positions we get to by skipping `x*` items.
+    fn closure(&self, mut states: States) -> States {
+        for (i, item) in self.items.iter().enumerate() {
+            if item.star && states & (1 << i) != 0 {
+                states |= 1 << (i + 1);
+            }
+        }
+        states
+    }
+}
+
+/// Split off the first atom of `re`.
+fn next_atom(re: &[u8]) -> (Atom, &[u8]) {
+    match re {
+        [b'\\', b, rest @ ..] => (Atom::Byte(*b), rest),
+        [b'.', rest @ ..] => (Atom::Any, rest),
+        [b, rest @ ..] => (Atom::Byte(*b), rest),
+        [] => unreachable!("called with empty pattern"),
+    }
+}
+
+/// Matches a pattern against a message as it's formatted.
+///
+/// Runs the pattern as an NFA, so it takes `O(message * pattern)` time no
+/// matter how many `*`s there are.
+pub(crate) struct Matcher<'a> {
+    pattern: &'a Pattern,
+    /// Positions in the pattern we can be at after the bytes so far.
+    states: States,
+    /// Matched already (the rest of the message doesn't matter).
+    matched: bool,
+}
+
+impl<'a> Matcher<'a> {
+    fn push(&mut self, b: u8) {
+        let pattern = self.pattern;
+        if self.states & pattern.done() != 0 && !pattern.end {
+            self.matched = true;
+            return;
+        }
+        let mut next = 0;
+        for (i, item) in pattern.items.iter().enumerate() {
+            if self.states & (1 << i) != 0 && item.atom.matches(b) {
+                next |= if item.star { 1 << i } else { 1 << (i + 1) };
+            }
+        }
+        if !pattern.start {
+            // A match can start anywhere
+            next |= 1;
+        }
+        self.states = pattern.closure(next);
+    }
+
+    /// Does the pattern match the message written so far?
+    pub(crate) fn is_match(&self) -> bool {
+        self.matched || self.states & self.pattern.done() != 0
+    }
+}
+
+impl<'a> fmt::Write for Matcher<'a> {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        for &b in s.as_bytes() {
+            if self.matched {
+                break;
+            }
+            self.push(b);
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use core::fmt::Write;
+
+    use super::Pattern;
+
+    fn is_match(pattern: &str, text: &str) -> bool {
+        Pattern::new(pattern).unwrap().is_match(text)
+    }
+
+    #[test]
+    fn substring() {
+        assert!(is_match("irq", "MSI-X irq 12 enabled"));
+        assert!(is_match("", "anything"));
+        assert!(!is_match("irq", "MSI-X enabled"));
+    }
+
+    #[test]
+    fn anchors() {
+        assert!(is_match("^MSI", "MSI-X irq"));
+        assert!(!is_match("^irq", "MSI-X irq"));
+        assert!(is_match("irq$", "MSI-X irq"));
+        assert!(!is_match("MSI$", "MSI-X irq"));
+        assert!(is_match("^$", ""));
+    }
+
+    #[test]
+    fn wildcards() {
+        assert!(is_match("irq .2", "irq 12"));
+        assert!(is_match("^core 1*2$", "core 2"));
+        assert!(is_match("^core 1*2$", "core 1112"));
+        assert!(is_match("page.*fault", "page 0x1000: fault"));
+        assert!(!is_match("page.*fault", "fault in page"));
+    }
+
+    #[test]
+    fn escapes() {
+        assert!(is_match(r"1\.5", "version 1.5"));
+        assert!(!is_match(r"1\.5", "version 105"));
+        assert!(is_match(r"\*", "a * b"));
+    }
+
+    #[test]
+    fn many_stars() {
+        // Exponential for a backtracking matcher
+        let text = "a".repeat(256);
+        assert!(!is_match("a*a*a*a*a*a*a*a*a*a*a*a*a*a*a*a*b", &text));
+        assert!(is_match("^a*a*a*a*a*a*a*a*a*a*a*a*a*a*a*a*$", &text));
+        assert!(is_match("x*y*z*", ""));
+        assert!(!is_match("^a*b$", "aab "));
+    }
+
+    #[test]
+    fn long_message() {
+        let pattern = Pattern::new("irq 3$").unwrap();
+        let mut matcher = pattern.matcher();
+        for _ in 0..1000 {
+            write!(matcher, "ab").unwrap();
+        }
+        assert!(!matcher.is_match());
+        write!(matcher, " irq {}", 3).unwrap();
+        assert!(matcher.is_match());
+    }
+}
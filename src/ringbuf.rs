@@ -0,0 +1,327 @@
+//! In-memory ring buffer that keeps the most recent output (dmesg-style).
+//!
+//! A `RingBuffer` is a [`Sink`] so it can be registered with `add_sink` (or
+//! `add_filtered_sink`) next to the serial line. Once full, the oldest lines
+//! are discarded to make room for new output (or the oldest bytes, if a
+//! single line doesn't fit).
+
+use core::fmt::Write;
+use core::mem::ManuallyDrop;
+use core::ops;
+use core::sync::atomic::{AtomicU64, Ordering};
+
+use heapless::Deque;
+
+use super::{arch, cpu_id, in_emergency, Sink, WriterNoDrop};
+
+/// `RingBuffer::dumper` if nobody dumps the buffer (CPU ids are only 32
+/// bits).
+const NOT_DUMPING: u64 = u64::MAX;
+
+/// Size of [`DMESG`] in bytes.
+#[cfg(feature = "dmesg")]
+pub const DMESG_SIZE: usize = 16 * 1024;
+
+/// Ring buffer provided by klogger (enabled by the `dmesg` feature).
+#[cfg(feature = "dmesg")]
+pub static DMESG: RingBuffer<DMESG_SIZE> = RingBuffer::new();
+
+/// Keeps the last `N` bytes of formatted output.
+pub struct RingBuffer<const N: usize> {
+    /// Only locked with interrupts disabled (see `Locked`).
+    buf: spin::Mutex<Deque<u8, N>>,
+    /// The core that writes the content out with `dump` right now.
+    ///
+    /// If the buffer itself is a registered sink, the dumped output would
+    /// be written back to it (and deadlock on `buf`), so we drop it instead.
+    /// Other cores wait until the dump is done.
+    dumper: AtomicU64,
+}
+
+/// The locked content of a `RingBuffer`.
+///
+/// Interrupts are disabled until it's dropped: an interrupt handler that
+/// logs (or calls `sprint!`) on this core would wait for the lock forever.
+struct Locked<'a, const N: usize> {
+    buf: ManuallyDrop<spin::MutexGuard<'a, Deque<u8, N>>>,
+    /// Interrupt state before we took the lock.
+    interrupts: arch::InterruptState,
+}
+
+impl<'a, const N: usize> ops::Deref for Locked<'a, N> {
+    type Target = Deque<u8, N>;
+
+    fn deref(&self) -> &Deque<u8, N> {
+        &self.buf
+    }
+}
+
+impl<'a, const N: usize> ops::DerefMut for Locked<'a, N> {
+    fn deref_mut(&mut self) -> &mut Deque<u8, N> {
+        &mut self.buf
+    }
+}
+
+impl<'a, const N: usize> ops::Drop for Locked<'a, N> {
+    /// Release the lock, then restore interrupts.
+    fn drop(&mut self) {
+        unsafe { ManuallyDrop::drop(&mut self.buf) };
+        arch::restore_interrupts(self.interrupts);
+    }
+}
+
+impl<const N: usize> RingBuffer<N> {
+    /// Create a new, empty ring buffer.
+    pub const fn new() -> Self {
+        RingBuffer {
+            buf: spin::Mutex::new(Deque::new()),
+            dumper: AtomicU64::new(NOT_DUMPING),
+        }
+    }
+
+    fn lock(&self) -> Locked<'_, N> {
+        let interrupts = arch::disable_interrupts();
+        Locked {
+            buf: ManuallyDrop::new(self.buf.lock()),
+            interrupts,
+        }
+    }
+
+    fn try_lock(&self) -> Option<Locked<'_, N>> {
+        let interrupts = arch::disable_interrupts();
+        match self.buf.try_lock() {
+            Some(buf) => Some(Locked {
+                buf: ManuallyDrop::new(buf),
+                interrupts,
+            }),
+            None => {
+                arch::restore_interrupts(interrupts);
+                None
+            }
+        }
+    }
+
+    /// Number of bytes currently stored.
+    pub fn len(&self) -> usize {
+        self.lock().len()
+    }
+
+    /// Is the buffer empty?
+    pub fn is_empty(&self) -> bool {
+        self.lock().is_empty()
+    }
+
+    /// Discard all stored output.
+    pub fn clear(&self) {
+        self.lock().clear();
+    }
+
+    /// Iterate over the stored output, oldest byte first.
+    ///
+    /// The buffer is locked (new output blocks) and interrupts are disabled
+    /// until the iterator is dropped.
+    pub fn iter(&self) -> Iter<'_, N> {
+        Iter {
+            buf: self.lock(),
+            pos: 0,
+        }
+    }
+
+    /// Call `f` with the stored output (oldest first) and remove it from the
+    /// buffer. `f` is called at most twice since the content can wrap around.
+    pub fn drain<F: FnMut(&[u8])>(&self, mut f: F) {
+        let mut buf = self.lock();
+        {
+            let (front, back) = buf.as_slices();
+            f(front);
+            if !back.is_empty() {
+                f(back);
+            }
+        }
+        buf.clear();
+    }
+
+    /// Write the stored output with `WriterNoDrop`.
+    ///
+    /// Meant to be used in panic handlers, so it doesn't wait for the buffer
+    /// lock: if the buffer is in use (e.g., we panicked while writing to it)
+    /// nothing is printed.
+    pub fn dump(&self) {
+        if let Some(buf) = self.try_lock() {
+            self.dumper.store(cpu_id() as u64, Ordering::Relaxed);
+            let (front, back) = buf.as_slices();
+            for part in [front, back] {
+                for chunk in part.utf8_chunks() {
+                    let _ = WriterNoDrop::get().write_str(chunk.valid());
+                }
+            }
+            self.dumper.store(NOT_DUMPING, Ordering::Relaxed);
+        }
+    }
+}
+
+impl<const N: usize> Default for RingBuffer<N> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<const N: usize> Sink for RingBuffer<N> {
+    fn write(&self, bytes: &[u8]) {
+        if self.dumper.load(Ordering::Relaxed) == cpu_id() as u64 {
+            return;
+        }
+
+        // We might have panicked while holding the lock
+        let mut buf = if in_emergency() {
+            match self.try_lock() {
+                Some(buf) => buf,
+                None => return,
+            }
+        } else {
+            self.lock()
+        };
+        // Once there's no newline left, there won't be one until we add it
+        let mut has_newline = true;
+        for &b in bytes {
+            if buf.is_full() {
+                // Evict the oldest line so the buffer always starts at the
+                // beginning of a line. A line longer than the buffer goes
+                // byte by byte, so we keep its tail instead of nothing.
+                let line = if has_newline {
+                    buf.iter().position(|&b| b == b'\n')
+                } else {
+                    None
+                };
+                has_newline = line.is_some();
+                for _ in 0..line.map_or(1, |end| end + 1) {
+                    buf.pop_front();
+                }
+            }
+            // Can't fail, we made room above
+            let _ = buf.push_back(b);
+            has_newline |= b == b'\n';
+        }
+    }
+}
+
+/// Iterator over the bytes stored in a [`RingBuffer`].
+pub struct Iter<'a, const N: usize> {
+    buf: Locked<'a, N>,
+    pos: usize,
+}
+
+impl<'a, const N: usize> Iterator for Iter<'a, N> {
+    type Item = u8;
+
+    fn next(&mut self) -> Option<u8> {
+        let (front, back) = self.buf.as_slices();
+        let b = if self.pos < front.len() {
+            front[self.pos]
+        } else {
+            *back.get(self.pos - front.len())?
+        };
+        self.pos += 1;
+        Some(b)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{RingBuffer, Sink};
+    #[cfg(all(not(feature = "use_ioports"), target_family = "unix"))]
+    use {
+        arch::{interrupts_enabled, restore_interrupts},
+        emergency,
+        lock::leave_emergency,
+        sink::test::REGISTRY,
+        sink::{add_sink, clear_sinks},
+    };
+
+    #[test]
+    fn keeps_everything_until_full() {
+        let rb: RingBuffer<16> = RingBuffer::new();
+        rb.write(b"line 1\r\n");
+        rb.write(b"line 2");
+
+        assert_eq!(rb.len(), 14);
+        assert!(rb.iter().eq(b"line 1\r\nline 2".iter().copied()));
+    }
+
+    #[test]
+    fn evicts_oldest_lines() {
+        let rb: RingBuffer<16> = RingBuffer::new();
+        rb.write(b"first\r\n");
+        rb.write(b"second\r\n");
+        rb.write(b"third\r\n");
+
+        assert!(rb.iter().eq(b"second\r\nthird\r\n".iter().copied()));
+    }
+
+    #[test]
+    fn keeps_tail_of_long_line() {
+        let rb: RingBuffer<8> = RingBuffer::new();
+        rb.write(b"ab\n");
+        rb.write(b"0123456789");
+        assert!(rb.iter().eq(b"23456789".iter().copied()));
+
+        // Back to whole lines once there is a newline
+        rb.write(b"\nxy");
+        assert!(rb.iter().eq(b"xy".iter().copied()));
+    }
+
+    #[test]
+    #[cfg(all(not(feature = "use_ioports"), target_family = "unix"))]
+    fn emergency_skips_locked_buffer() {
+        let _registry = REGISTRY.lock();
+        let rb: RingBuffer<16> = RingBuffer::new();
+        // Held by a core that died while writing to it
+        let buf = rb.buf.lock();
+        emergency();
+        rb.write(b"panicked\r\n");
+        leave_emergency();
+        restore_interrupts(true);
+        drop(buf);
+        assert!(rb.is_empty());
+    }
+
+    #[test]
+    #[cfg(all(not(feature = "use_ioports"), target_family = "unix"))]
+    fn locked_without_interrupts() {
+        let rb: RingBuffer<16> = RingBuffer::new();
+        rb.write(b"abc");
+        let iter = rb.iter();
+        assert!(!interrupts_enabled());
+        drop(iter);
+        assert!(interrupts_enabled());
+    }
+
+    #[test]
+    #[cfg(all(not(feature = "use_ioports"), target_family = "unix"))]
+    fn dump_skips_own_output() {
+        static RB: RingBuffer<32> = RingBuffer::new();
+        let _registry = REGISTRY.lock();
+        add_sink(&RB).expect("add sink");
+
+        RB.write(b"line 1\r\n");
+        RB.dump();
+        sprint!("line 2\r\n");
+        clear_sinks();
+
+        assert!(RB.iter().eq(b"line 1\r\nline 2\r\n".iter().copied()));
+    }
+
+    #[test]
+    fn drain_empties_buffer() {
+        let rb: RingBuffer<8> = RingBuffer::new();
+        rb.write(b"abc\n");
+        rb.write(b"defg");
+        rb.write(b"h");
+
+        let mut out: heapless::Vec<u8, 16> = heapless::Vec::new();
+        rb.drain(|part| out.extend_from_slice(part).unwrap());
+
+        assert_eq!(&out[..], b"defgh");
+        assert!(rb.is_empty());
+    }
+}
@@ -0,0 +1,392 @@
+//! Output sinks that receive everything klogger prints.
+//!
+//! By default all output goes to the built-in [`Console`](crate::Console)
+//! of the architecture (serial port on x86, PL011 UART on aarch64, stdout
+//! on unix). Once one or more sinks are registered with [`add_sink`], output
+//! is sent to those sinks instead (register `Console` explicitly to keep it).
+//!
+//! Every sink can have its own filter so, e.g., errors go to the serial line
+//! while everything gets recorded in a memory buffer.
+
+use core::fmt;
+use core::fmt::Write;
+
+use heapless::Vec;
+use log::Metadata;
+
+use super::{arch, enabled, in_emergency, parse_args, ColorMode, Directives, Error};
+use lock;
+use pattern::{Matcher, Pattern};
+
+/// Maximum number of sinks that can be registered at the same time.
+pub const MAX_SINKS: usize = 4;
+
+/// A destination for klogger output (e.g., a UART, a framebuffer console
+/// or a memory buffer).
+///
+/// Sinks are shared between cores so any internal state needs interior
+/// mutability. Lines written through [`Writer`](crate::Writer) are
+/// serialized by `SERIAL_LINE_MUTEX` already. Sinks with a lock of their
+/// own must not wait for it in emergency mode (see
+/// [`in_emergency`](crate::in_emergency)), its holder may never let go.
+pub trait Sink: Sync {
+    /// Write `bytes` to the sink.
+    fn write(&self, bytes: &[u8]);
+
+    /// Flush any output the sink may have buffered.
+    fn flush(&self) {}
+
+    /// Can the sink accept output right now?
+    ///
+    /// Sinks that are not ready (e.g., a framebuffer that is not mapped yet)
+    /// are skipped.
+    fn is_ready(&self) -> bool {
+        true
+    }
+}
+
+/// A registered sink along with the log records it wants to see.
+pub(crate) struct Output {
+    sink: &'static dyn Sink,
+    /// Directives applied to log records on top of the global filter.
+    ///
+    /// Empty means everything that passes the global filter.
+    filter: Directives,
+    /// Only log records whose message matches.
+    pattern: Option<Pattern>,
+    /// Overrides the global colour mode for this sink.
+    color: Option<ColorMode>,
+}
+
+impl Output {
+    /// `sink` with the filter `spec` (see `add_filtered_sink`).
+    pub(crate) fn new(sink: &'static dyn Sink, spec: &str) -> Result<Output, Error> {
+        let mut filter = Directives::new();
+        let spec = parse_args(&mut filter, spec)?;
+        Ok(Output {
+            sink,
+            filter,
+            pattern: spec.pattern,
+            color: spec.color,
+        })
+    }
+
+    fn enabled(&self, metadata: &Metadata) -> bool {
+        self.filter.is_empty() || enabled(&self.filter, metadata.level(), metadata.target())
+    }
+}
+
+/// Feeds a message to the matchers of all sinks at once.
+struct Matchers<'a, 'p>(&'a mut [Option<Matcher<'p>>]);
+
+impl<'a, 'p> fmt::Write for Matchers<'a, 'p> {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        for matcher in self.0.iter_mut().flatten() {
+            matcher.write_str(s)?;
+        }
+        Ok(())
+    }
+}
+
+/// Currently registered sinks, if empty we write to `arch::Console`.
+static SINKS: spin::RwLock<Vec<Output, MAX_SINKS>> = spin::RwLock::new(Vec::new());
+
+/// Register a new sink that receives all subsequent output.
+pub fn add_sink(sink: &'static dyn Sink) -> Result<(), Error> {
+    add_filtered_sink(sink, "")
+}
+
+/// Register a new sink that only receives log records matching `spec`.
+///
+/// `spec` uses the same syntax as the filter passed to `init` (e.g.,
+/// "warn", "info,crate1::mod1=trace" or "debug/irq"); records still have to pass the
+/// global filter first. Output that doesn't come from the `log` crate
+/// (`sprintln!` etc.) is written to the sink unconditionally.
+///
+/// A `color=` directive sets the colour mode of just this sink (e.g.,
+/// "trace,color=never" for a sink that ends up in a file).
+pub fn add_filtered_sink(sink: &'static dyn Sink, spec: &str) -> Result<(), Error> {
+    let output = Output::new(sink, spec)?;
+    lock::without_interrupts(|| SINKS.write().push(output).map_err(|_| Error::TooManySinks))
+}
+
+/// Register all of `outputs` once `register` (e.g., setting the logger)
+/// succeeded, or none if they don't fit or it fails.
+///
+/// The sinks stay locked meanwhile, so nobody takes the room we checked.
+pub(crate) fn add_outputs_with<F: FnOnce() -> Result<(), Error>>(
+    outputs: Vec<Output, MAX_SINKS>,
+    register: F,
+) -> Result<(), Error> {
+    lock::without_interrupts(|| {
+        let mut sinks = SINKS.write();
+        if sinks.len() + outputs.len() > MAX_SINKS {
+            return Err(Error::TooManySinks);
+        }
+        register()?;
+        for output in outputs {
+            // Can't fail, we checked above
+            let _ = sinks.push(output);
+        }
+        Ok(())
+    })
+}
+
+/// Remove all registered sinks, output goes to the built-in `Console` again.
+pub fn clear_sinks() {
+    lock::without_interrupts(|| SINKS.write().clear());
+}
+
+/// The registered sinks, `None` in emergency mode if they can't be read
+/// right away.
+fn sinks() -> Option<spin::RwLockReadGuard<'static, Vec<Output, MAX_SINKS>>> {
+    if in_emergency() {
+        SINKS.try_read()
+    } else {
+        Some(SINKS.read())
+    }
+}
+
+/// Write `bytes` to all registered sinks.
+///
+/// In emergency mode the sinks are skipped for the console if they are
+/// being changed right now (we might have panicked doing that).
+pub(crate) fn write(bytes: &[u8]) {
+    let sinks = match sinks() {
+        Some(sinks) => sinks,
+        None => return arch::Console.write(bytes),
+    };
+    if sinks.is_empty() {
+        arch::Console.write(bytes);
+    } else {
+        for output in sinks.iter().filter(|o| o.sink.is_ready()) {
+            output.sink.write(bytes);
+        }
+    }
+}
+
+/// Flush all registered sinks.
+pub(crate) fn flush() {
+    let sinks = match sinks() {
+        Some(sinks) => sinks,
+        None => return arch::Console.flush(),
+    };
+    if sinks.is_empty() {
+        arch::Console.flush();
+    } else {
+        for output in sinks.iter() {
+            output.sink.flush();
+        }
+    }
+}
+
+/// Call `f` for every sink that wants to see log records with `metadata`
+/// (along with the colour mode of the sink, if it has one).
+///
+/// `message` is the message of the record, it's formatted once for all
+/// sinks with a message pattern. If it's `None` these sinks get the record
+/// as well.
+pub(crate) fn for_each_enabled<F: FnMut(&dyn Sink, Option<ColorMode>)>(
+    metadata: &Metadata,
+    message: Option<&fmt::Arguments>,
+    mut f: F,
+) {
+    let sinks = match sinks() {
+        Some(sinks) => sinks,
+        None => return f(&arch::Console, None),
+    };
+    if sinks.is_empty() {
+        return f(&arch::Console, None);
+    }
+
+    let mut matchers: Vec<Option<Matcher>, MAX_SINKS> = sinks
+        .iter()
+        .map(|o| o.pattern.as_ref().map(Pattern::matcher))
+        .collect();
+    if let Some(message) = message {
+        if matchers.iter().any(Option::is_some) {
+            let _ = write!(Matchers(&mut matchers), "{}", message);
+        }
+    }
+    for (output, matcher) in sinks.iter().zip(&matchers) {
+        let matches = match (matcher, message) {
+            (Some(matcher), Some(_)) => matcher.is_match(),
+            _ => true,
+        };
+        if matches && output.sink.is_ready() && output.enabled(metadata) {
+            f(output.sink, output.color);
+        }
+    }
+}
+
+/// Adapter to use a single sink with `core::fmt`.
+pub(crate) struct SinkWriter<'a>(pub &'a dyn Sink);
+
+impl<'a> fmt::Write for SinkWriter<'a> {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        self.0.write(s.as_bytes());
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+pub(crate) mod test {
+    use heapless::Vec;
+    use log::{Level, Metadata};
+
+    use super::{add_filtered_sink, add_sink, clear_sinks, for_each_enabled, Sink};
+    #[cfg(all(not(feature = "use_ioports"), target_family = "unix"))]
+    use {
+        arch::restore_interrupts, emergency, lock::leave_emergency, set_filter, sink::SINKS,
+        KLoggerBuilder, LOGGER,
+    };
+
+    /// Serializes tests that modify the global sink registry (or need the
+    /// output line to themselves).
+    pub(crate) static REGISTRY: spin::Mutex<()> = spin::Mutex::new(());
+
+    /// Keeps everything written to it (up to `N` bytes).
+    pub(crate) struct Memory<const N: usize>(pub(crate) spin::Mutex<Vec<u8, N>>);
+
+    impl<const N: usize> Memory<N> {
+        pub(crate) const fn new() -> Self {
+            Memory(spin::Mutex::new(Vec::new()))
+        }
+    }
+
+    impl<const N: usize> Sink for Memory<N> {
+        fn write(&self, bytes: &[u8]) {
+            self.0
+                .lock()
+                .extend_from_slice(bytes)
+                .expect("memory sink is full");
+        }
+    }
+
+    #[test]
+    fn registered_sink_receives_output() {
+        static MEMORY: Memory<64> = Memory::new();
+        let _registry = REGISTRY.lock();
+
+        add_sink(&MEMORY).expect("add sink");
+        sprint!("hello {}", 42);
+        clear_sinks();
+        sprint!(" not captured");
+
+        assert_eq!(&MEMORY.0.lock()[..], b"hello 42");
+    }
+
+    #[test]
+    fn fan_out_respects_sink_filters() {
+        static SERIAL: Memory<64> = Memory::new();
+        static BUFFER: Memory<64> = Memory::new();
+        let _registry = REGISTRY.lock();
+
+        add_filtered_sink(&SERIAL, "warn").expect("add sink");
+        add_filtered_sink(&BUFFER, "trace,crate1=off").expect("add sink");
+        for (level, target) in [
+            (Level::Error, "crate2"),
+            (Level::Info, "crate2"),
+            (Level::Error, "crate1"),
+        ] {
+            let metadata = Metadata::builder().level(level).target(target).build();
+            for_each_enabled(&metadata, None, |sink, _| sink.write(&[level as u8]));
+        }
+        clear_sinks();
+
+        assert_eq!(&SERIAL.0.lock()[..], &[1, 1]);
+        assert_eq!(&BUFFER.0.lock()[..], &[1, 3]);
+    }
+
+    #[test]
+    fn sink_message_pattern() {
+        static IRQS: Memory<64> = Memory::new();
+        let _registry = REGISTRY.lock();
+
+        add_filtered_sink(&IRQS, "info/^irq").expect("add sink");
+        let metadata = Metadata::builder().level(Level::Info).target("pci").build();
+        for (i, message) in ["irq 3 enabled", "bar 0 mapped", "irq 4 enabled"]
+            .iter()
+            .enumerate()
+        {
+            let message = format_args!("{}", message);
+            for_each_enabled(&metadata, Some(&message), |sink, _| sink.write(&[i as u8]));
+        }
+        clear_sinks();
+
+        assert_eq!(&IRQS.0.lock()[..], &[0, 2]);
+    }
+
+    #[test]
+    fn sink_color_mode() {
+        use ColorMode;
+
+        static PLAIN: Memory<64> = Memory::new();
+        static DEFAULT: Memory<64> = Memory::new();
+        let _registry = REGISTRY.lock();
+
+        add_filtered_sink(&PLAIN, "info,color=never").expect("add sink");
+        add_sink(&DEFAULT).expect("add sink");
+        let metadata = Metadata::builder().level(Level::Info).target("pci").build();
+        for_each_enabled(&metadata, None, |sink, color| {
+            sink.write(&[(color == Some(ColorMode::Never)) as u8])
+        });
+        clear_sinks();
+
+        assert_eq!(&PLAIN.0.lock()[..], &[1]);
+        assert_eq!(&DEFAULT.0.lock()[..], &[0]);
+    }
+
+    #[test]
+    #[cfg(all(not(feature = "use_ioports"), target_family = "unix"))]
+    fn emergency_skips_busy_registry() {
+        static MEMORY: Memory<64> = Memory::new();
+        let _registry = REGISTRY.lock();
+        add_sink(&MEMORY).expect("add sink");
+
+        // We panicked while changing the sinks
+        let sinks = SINKS.write();
+        emergency();
+        sprint!("to the console");
+        leave_emergency();
+        restore_interrupts(true);
+        drop(sinks);
+        clear_sinks();
+
+        assert!(MEMORY.0.lock().is_empty());
+    }
+
+    #[test]
+    #[cfg(all(not(feature = "use_ioports"), target_family = "unix"))]
+    fn emergency_logs_with_busy_config() {
+        static MEMORY: Memory<256> = Memory::new();
+        let _registry = REGISTRY.lock();
+        // Fails if another test installed klogger already, that's fine
+        let _ = KLoggerBuilder::new().filter("off").install();
+        set_filter("info").expect("set filter");
+        add_sink(&MEMORY).expect("add sink");
+
+        // We panicked while changing the config, the record still goes out
+        // (with the default format)
+        let config = LOGGER.config.write();
+        emergency();
+        log::info!("panicked");
+        // Also if the sinks are busy (to the console then)
+        let sinks = SINKS.write();
+        log::info!("to the console");
+        leave_emergency();
+        restore_interrupts(true);
+        drop(sinks);
+        drop(config);
+        clear_sinks();
+        set_filter("off").expect("set filter");
+
+        let memory = MEMORY.0.lock();
+        let line = core::str::from_utf8(&memory).unwrap();
+        assert!(
+            line.ends_with("klogger::sink::test: panicked\r\n"),
+            "{}",
+            line
+        );
+    }
+}
@@ -0,0 +1,226 @@
+//! Colours of log lines.
+//!
+//! A `Theme` assigns a colour to each part of a log line. Colours can be
+//! one of the 16 standard terminal colours, one of the 256 colours of the
+//! xterm palette or a 24-bit truecolour value, so there are themes for any
+//! terminal (e.g., `Theme::BASIC` only uses the standard colours).
+//!
+//! CPU ids can have a colour per core, e.g.,
+//! `Theme { cpus: &theme::CPU_COLORS, ..Theme::DARK }`.
+
+use core::fmt;
+
+use log::Level;
+#[cfg(feature = "colors")]
+use termcodes::color;
+
+/// A terminal foreground colour.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Color {
+    /// Don't change the colour.
+    Default,
+    Black,
+    Red,
+    Green,
+    Yellow,
+    Blue,
+    Magenta,
+    Cyan,
+    White,
+    LightBlack,
+    LightRed,
+    LightGreen,
+    LightYellow,
+    LightBlue,
+    LightMagenta,
+    LightCyan,
+    LightWhite,
+    /// Colour of the 256-colour (xterm) palette.
+    Ansi256(u8),
+    /// 24-bit truecolour.
+    Rgb(u8, u8, u8),
+}
+
+impl Color {
+    /// Index of one of the 16 standard colours (0-7 normal, 8-15 light).
+    #[cfg(feature = "colors")]
+    fn standard_index(self) -> u8 {
+        match self {
+            Color::Black => 0,
+            Color::Red => 1,
+            Color::Green => 2,
+            Color::Yellow => 3,
+            Color::Blue => 4,
+            Color::Magenta => 5,
+            Color::Cyan => 6,
+            Color::White => 7,
+            Color::LightBlack => 8,
+            Color::LightRed => 9,
+            Color::LightGreen => 10,
+            Color::LightYellow => 11,
+            Color::LightBlue => 12,
+            Color::LightMagenta => 13,
+            Color::LightCyan => 14,
+            Color::LightWhite => 15,
+            Color::Default | Color::Ansi256(_) | Color::Rgb(..) => {
+                unreachable!("not a standard colour")
+            }
+        }
+    }
+}
+
+/// Distinct colours for CPU ids (see `Theme::cpus`).
+pub const CPU_COLORS: [Color; 6] = [
+    Color::LightCyan,
+    Color::LightMagenta,
+    Color::LightGreen,
+    Color::LightBlue,
+    Color::LightRed,
+    Color::LightYellow,
+];
+
+/// Colours of the parts of a log line.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Theme {
+    pub error: Color,
+    pub warn: Color,
+    pub info: Color,
+    pub debug: Color,
+    pub trace: Color,
+    pub timestamp: Color,
+    pub cpu: Color,
+    /// If not empty, CPU `n` gets colour `cpus[n % cpus.len()]` (instead of
+    /// `cpu`).
+    pub cpus: &'static [Color],
+    pub target: Color,
+    pub message: Color,
+}
+
+impl Theme {
+    /// The default, meant for dark backgrounds.
+    pub const DARK: Theme = Theme {
+        error: Color::Ansi256(202),
+        warn: Color::Ansi256(167),
+        info: Color::Ansi256(136),
+        debug: Color::Ansi256(64),
+        trace: Color::Ansi256(32),
+        timestamp: Color::LightYellow,
+        cpu: Color::Default,
+        cpus: &[],
+        target: Color::Default,
+        message: Color::LightWhite,
+    };
+
+    /// For light backgrounds.
+    pub const LIGHT: Theme = Theme {
+        error: Color::Ansi256(160),
+        warn: Color::Ansi256(130),
+        info: Color::Ansi256(28),
+        debug: Color::Ansi256(25),
+        trace: Color::Ansi256(90),
+        timestamp: Color::Ansi256(242),
+        cpu: Color::Default,
+        cpus: &[],
+        target: Color::Default,
+        message: Color::Black,
+    };
+
+    /// Only uses the 16 standard colours.
+    pub const BASIC: Theme = Theme {
+        error: Color::LightRed,
+        warn: Color::Yellow,
+        info: Color::Green,
+        debug: Color::Cyan,
+        trace: Color::Blue,
+        timestamp: Color::LightBlack,
+        cpu: Color::Default,
+        cpus: &[],
+        target: Color::Default,
+        message: Color::Default,
+    };
+
+    /// Colour of the `level` of a line.
+    pub fn level(&self, level: Level) -> Color {
+        match level {
+            Level::Error => self.error,
+            Level::Warn => self.warn,
+            Level::Info => self.info,
+            Level::Debug => self.debug,
+            Level::Trace => self.trace,
+        }
+    }
+
+    /// Colour of the CPU id `cpu`.
+    pub fn cpu(&self, cpu: u32) -> Color {
+        match self.cpus.len() {
+            0 => self.cpu,
+            n => self.cpus[cpu as usize % n],
+        }
+    }
+}
+
+impl Default for Theme {
+    fn default() -> Theme {
+        Theme::DARK
+    }
+}
+
+/// Prints `value` in colour `color`.
+#[cfg_attr(not(feature = "colors"), allow(dead_code))]
+pub(crate) struct Painted<T>(pub Color, pub T);
+
+#[cfg(feature = "colors")]
+impl<T: fmt::Display> fmt::Display for Painted<T> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self.0 {
+            Color::Default => return self.1.fmt(f),
+            Color::Ansi256(value) => write!(f, "{}", color::Fg(color::AnsiValue(value)))?,
+            Color::Rgb(r, g, b) => write!(f, "{}", color::Fg(color::Rgb(r, g, b)))?,
+            // termcodes prints the standard colours as palette entries
+            // (`38;5;n`), which 16-colour terminals don't understand
+            standard => {
+                let index = standard.standard_index();
+                let sgr = if index < 8 {
+                    30 + index
+                } else {
+                    90 + index - 8
+                };
+                write!(f, "\x1b[{}m", sgr)?
+            }
+        }
+        write!(f, "{}{}", self.1, color::Fg(color::Reset))
+    }
+}
+
+/// Without the `colors` feature we can't print colours.
+#[cfg(not(feature = "colors"))]
+impl<T: fmt::Display> fmt::Display for Painted<T> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        self.1.fmt(f)
+    }
+}
+
+#[cfg(all(test, feature = "colors"))]
+mod test {
+    use std::format;
+
+    use super::{Color, Painted};
+
+    #[test]
+    fn escape_codes() {
+        assert_eq!(format!("{}", Painted(Color::Default, "x")), "x");
+        assert_eq!(format!("{}", Painted(Color::Red, "x")), "\x1b[31mx\x1b[39m");
+        assert_eq!(
+            format!("{}", Painted(Color::LightRed, "x")),
+            "\x1b[91mx\x1b[39m"
+        );
+        assert_eq!(
+            format!("{}", Painted(Color::Ansi256(202), "x")),
+            "\x1b[38;5;202mx\x1b[39m"
+        );
+        assert_eq!(
+            format!("{}", Painted(Color::Rgb(1, 2, 3), "x")),
+            "\x1b[38;2;1;2;3mx\x1b[39m"
+        );
+    }
+}
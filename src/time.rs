@@ -0,0 +1,110 @@
+//! Conversion of cycle counts to wall-clock time.
+
+/// One sec has that many ns.
+pub const NS_PER_SEC: u64 = 1_000_000_000u64;
+
+/// Converts cycles of a fixed-frequency counter (e.g., an invariant TSC) to
+/// nanoseconds.
+///
+/// Similar to a Linux clocksource, the conversion is a multiplication
+/// followed by a shift (`ns = (cycles * mult) >> shift`) where `mult` and
+/// `shift` are computed once from the frequency. The product is done in 128
+/// bits so it can't overflow for any 64-bit cycle count.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct CyclesToNs {
+    mult: u64,
+    shift: u32,
+}
+
+impl CyclesToNs {
+    /// Create a converter for a counter running at `frequency_hz`.
+    ///
+    /// Returns `None` if the frequency is zero.
+    pub const fn new(frequency_hz: u64) -> Option<CyclesToNs> {
+        if frequency_hz == 0 {
+            return None;
+        }
+
+        // Use the largest shift for which `mult` still fits in 64 bits to
+        // keep as much precision as possible.
+        let mut shift = 64;
+        loop {
+            let mult = ((NS_PER_SEC as u128) << shift) / frequency_hz as u128;
+            if mult <= u64::MAX as u128 {
+                return Some(CyclesToNs {
+                    mult: mult as u64,
+                    shift,
+                });
+            }
+            shift -= 1;
+        }
+    }
+
+    /// Convert `cycles` to nanoseconds (saturates at `u64::MAX`).
+    pub fn to_ns(self, cycles: u64) -> u64 {
+        let ns = (cycles as u128 * self.mult as u128) >> self.shift;
+        if ns > u64::MAX as u128 {
+            u64::MAX
+        } else {
+            ns as u64
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{CyclesToNs, NS_PER_SEC};
+
+    /// Reference result with a 128-bit division.
+    fn exact_ns(cycles: u64, frequency_hz: u64) -> u64 {
+        (cycles as u128 * NS_PER_SEC as u128 / frequency_hz as u128) as u64
+    }
+
+    #[test]
+    fn zero_frequency() {
+        assert_eq!(CyclesToNs::new(0), None);
+    }
+
+    #[test]
+    fn one_ghz_is_identity() {
+        let conv = CyclesToNs::new(NS_PER_SEC).unwrap();
+        assert_eq!(conv.to_ns(0), 0);
+        assert_eq!(conv.to_ns(1), 1);
+        assert_eq!(conv.to_ns(123_456_789_012), 123_456_789_012);
+    }
+
+    #[test]
+    fn multi_ghz_one_second() {
+        for &freq in &[2_000_000_000, 2_593_906_000, 3_600_000_000, 4_999_999_999] {
+            let conv = CyclesToNs::new(freq).unwrap();
+            let ns = conv.to_ns(freq);
+            assert!(
+                ns == NS_PER_SEC || ns == NS_PER_SEC - 1,
+                "{} Hz: {}",
+                freq,
+                ns
+            );
+        }
+    }
+
+    #[test]
+    fn months_of_uptime_do_not_overflow() {
+        // Six months at 5 GHz is ~7.8e16 cycles, (cycles * NS_PER_SEC) would
+        // overflow 64 bits after ~3.7 s.
+        let six_months_s = 6 * 31 * 24 * 3600;
+        for &freq in &[1_000_000, 24_000_000, 2_100_000_000, 5_000_000_000] {
+            let conv = CyclesToNs::new(freq).unwrap();
+            let cycles = six_months_s * freq;
+            let ns = conv.to_ns(cycles);
+            let exact = exact_ns(cycles, freq);
+            assert!(exact - ns <= 1, "{} Hz: {} vs {}", freq, ns, exact);
+            assert_eq!(exact, six_months_s * NS_PER_SEC);
+        }
+    }
+
+    #[test]
+    fn saturates() {
+        let conv = CyclesToNs::new(1).unwrap();
+        assert_eq!(conv.to_ns(u64::MAX), u64::MAX);
+    }
+}